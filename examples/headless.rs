@@ -1,18 +1,22 @@
 #![cfg(unix)]
 
 use clap::{Parser, ValueHint};
-use core::ptr::null_mut;
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 use nes_emulator::{
+    av_recorder::AvRecorder,
     common::Clocked,
+    four_score::FourPlayerAdapter,
     headless_protocol::{
         Command::{self, *},
         ReadWrite, RenderStyle, StdInOut,
     },
+    input::{InputConfig, Inputs, PortDevice},
     joystick::Joystick,
     mapper::AddressSpace,
-    nes::{load_ines, read_ines, Nes},
+    nes::{load_ines, read_ines, savestate_input_config, Nes},
+    png::{screenshot, write_png},
     serialization::{read_value, Savable},
+    video::VideoPipeline,
 };
 use std::{
     fs::File,
@@ -108,41 +112,60 @@ fn main() {
 }
 
 struct Headless {
-    joystick1: *mut Joystick,
-    joystick2: *mut Joystick,
+    inputs: Option<Inputs>,
     nes: Option<Box<Nes>>,
     fh: Box<dyn ReadWrite>,
     is_synchronized: bool,
     num_commands: u64,
     is_rendering: bool,
+    av_recorder: Option<AvRecorder>,
 }
 
 impl Headless {
     pub fn new<RW: ReadWrite + 'static>(fh: RW) -> Self {
         let nes = None;
         Self {
-            joystick1: null_mut(),
-            joystick2: null_mut(),
+            inputs: None,
             nes: nes,
             fh: Box::new(fh),
             is_synchronized: true,
             num_commands: 0,
             is_rendering: true,
+            av_recorder: None,
         }
     }
+    // Replaces the devices in both ports, keeping the buttons held on each controller
+    fn connect_inputs(&mut self, config: InputConfig) {
+        let nes = self.nes.as_mut().unwrap();
+        let buttons = self
+            .inputs
+            .as_ref()
+            .map_or([0; 4], |inputs| inputs.joystick_buttons());
+        let mut inputs = Inputs::connect(nes, config);
+        for (controller_id, buttons) in buttons.iter().enumerate() {
+            if let Some(joystick) = inputs.joystick(controller_id) {
+                joystick.set_buttons(*buttons);
+            }
+        }
+        self.inputs = Some(inputs);
+    }
+    fn input_config(&self) -> InputConfig {
+        self.inputs.as_ref().unwrap().config
+    }
     fn dispatch_command(&mut self, command: Command) {
         debug!("Received command: {:?}", command);
         match command {
             LoadRom(_, filename) => {
-                let mut joystick1 = Box::new(Joystick::new());
-                let mut joystick2 = Box::new(Joystick::new());
-                self.joystick1 = &mut *joystick1;
-                self.joystick2 = &mut *joystick2;
                 match read_ines(filename.clone()) {
                     Ok(ines) => {
+                        let joystick1 = Box::new(Joystick::new());
+                        let joystick2 = Box::new(Joystick::new());
                         let mut nes = load_ines(ines, joystick1, joystick2);
                         nes.apu.is_recording = false; // TODO - Expose some way to retrieve recorded sound
+                        let config = nes.default_input().unwrap_or_default();
                         self.nes = Some(Box::new(nes));
+                        self.inputs = None;
+                        self.connect_inputs(config);
                     }
                     x @ Err { .. } => panic!("Error loading rom file {:?} - {:?}", filename, x),
                 }
@@ -153,31 +176,61 @@ impl Headless {
                 } else {
                     self.nes.as_mut().unwrap().run_frame_headless();
                 }
+                if let Some(recorder) = self.av_recorder.as_mut() {
+                    let nes = self.nes.as_mut().unwrap();
+                    recorder
+                        .record_frame(&nes.ppu, &nes.apu.samples)
+                        .expect("Unable to record frame");
+                    nes.apu.samples.clear();
+                }
             }
             RenderFrame(render_style) => {
-                let bytes: Vec<u8> = match render_style {
-                    RenderStyle::Plain => self.nes.as_ref().unwrap().ppu.display.to_vec(),
-                    RenderStyle::Rgb => self.nes.as_ref().unwrap().ppu.render().to_vec(),
-                };
+                let mut bytes: Vec<u8> = vec![0; render_style.frame_size()];
+                self.nes.as_ref().unwrap().ppu.render_into(
+                    &mut bytes,
+                    render_style.pixel_format(),
+                    None,
+                );
                 self.fh
                     .write(&bytes)
                     .expect(&*format!("Unable to write bytes for {:?}", render_style));
             }
             SetInputs(controller_id, button_mask) => {
-                assert_eq!(
-                    controller_id, 0,
+                assert!(
+                    controller_id < 4,
                     "Unsupported controller_id {}",
                     controller_id
                 );
-                unsafe { (*self.joystick1).set_buttons(button_mask) };
+                // Controllers 3 and 4 plug in through a Four Score
+                let config = self.input_config();
+                if controller_id >= 2 && config.four_players.is_none() {
+                    self.connect_inputs(InputConfig {
+                        four_players: Some(FourPlayerAdapter::FourScore),
+                        ..config
+                    });
+                }
+                let inputs = self.inputs.as_mut().unwrap();
+                match inputs.joystick(controller_id as usize) {
+                    Some(joystick) => joystick.set_buttons(button_mask),
+                    None => panic!("Controller {} is unplugged", controller_id),
+                }
             }
             SaveState(filename) => {
                 let mut file = File::create(filename).unwrap();
                 self.nes.as_ref().unwrap().save(&mut file);
             }
             LoadState(filename) => {
-                let mut file = File::open(filename).unwrap();
-                self.nes.as_mut().unwrap().load(&mut file);
+                let savestate = std::fs::read(&filename).unwrap();
+                let result = savestate_input_config(&savestate).and_then(|config| {
+                    if config != self.input_config() {
+                        self.connect_inputs(config);
+                    }
+                    let nes = self.nes.as_mut().unwrap();
+                    nes.load_state(&mut savestate.as_slice())
+                });
+                if let Err(e) = result {
+                    warn!("Not loading incompatible savestate {}: {}", filename, e);
+                }
             }
             GetInfo => panic!("Unimplemented"),
             Step => self.nes.as_mut().unwrap().clock(),
@@ -189,6 +242,67 @@ impl Headless {
             }
             Poke(address, value) => self.nes.as_mut().unwrap().cpu.poke(address, value),
             SetRendering(is_rendering) => self.is_rendering = is_rendering,
+            Screenshot(filename, scaler) => {
+                let mut video = VideoPipeline::new(None, scaler, 0.0);
+                let png = screenshot(&self.nes.as_ref().unwrap().ppu, Some(&mut video));
+                write_png(&filename, &png)
+                    .expect(&*format!("Unable to write screenshot {}", filename));
+            }
+            StartAvRecording(basename) => {
+                let nes = self.nes.as_mut().unwrap();
+                nes.apu.samples.clear();
+                nes.apu.is_recording = true;
+                self.av_recorder = Some(
+                    AvRecorder::create(&basename, nes.region())
+                        .expect(&*format!("Unable to start recording {}", basename)),
+                );
+            }
+            SetLayers(layers) => self.nes.as_mut().unwrap().ppu.set_layers(layers),
+            SetZapper(x, y, is_trigger_pulled) => {
+                let mut config = self.input_config();
+                if config.ports[1] != PortDevice::Zapper {
+                    config.ports[1] = PortDevice::Zapper;
+                    self.connect_inputs(config);
+                }
+                let zapper = self.inputs.as_mut().unwrap().zapper(1).unwrap();
+                zapper.set_pointer(Some((x, y)));
+                zapper.set_trigger(is_trigger_pulled);
+            }
+            SetPortDevice(port, device) => {
+                assert!(port < 2, "Unsupported port {}", port);
+                let mut config = self.input_config();
+                config.ports[port as usize] = device;
+                self.connect_inputs(config);
+            }
+            SetArkanoid(port, position, is_button_pressed) => {
+                let inputs = self.inputs.as_mut().unwrap();
+                let arkanoid = inputs
+                    .arkanoid(port as usize)
+                    .unwrap_or_else(|| panic!("No Arkanoid controller in port {}", port));
+                arkanoid.set_position(position);
+                arkanoid.set_button(is_button_pressed);
+            }
+            SetPowerPad(port, buttons) => {
+                let inputs = self.inputs.as_mut().unwrap();
+                let power_pad = inputs
+                    .power_pad(port as usize)
+                    .unwrap_or_else(|| panic!("No Power Pad in port {}", port));
+                power_pad.set_buttons(buttons);
+            }
+            SetUnlimitedSprites(unlimited_sprites) => self
+                .nes
+                .as_mut()
+                .unwrap()
+                .ppu
+                .set_unlimited_sprites(unlimited_sprites),
+            StopAvRecording => {
+                let nes = self.nes.as_mut().unwrap();
+                nes.apu.is_recording = false;
+                nes.apu.samples.clear();
+                if let Some(recorder) = self.av_recorder.take() {
+                    recorder.finish().expect("Unable to finish recording");
+                }
+            }
         }
     }
 
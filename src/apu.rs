@@ -5,6 +5,7 @@
 
 use crate::common::{get_bit, ternary, Clocked};
 use crate::mapper::AddressSpace;
+use crate::region::Region;
 use crate::serialization::Savable;
 
 use std::collections::VecDeque;
@@ -43,8 +44,7 @@ const ENABLE_TRIANGLE: bool = true;
 const ENABLE_NOISE: bool = true;
 const ENABLE_DMC: bool = false;
 
-const SAMPLES_PER_FRAME: f64 = 735.0; // 44100 Hz audio / 60 FPS
-const CLOCKS_PER_FRAME: f64 = 29780.0;
+pub const AUDIO_SAMPLE_RATE: f64 = 44100.0;
 
 pub fn map_apu_port(ptr: u16) -> Option<ApuPort> {
     match ptr {
@@ -74,11 +74,17 @@ pub fn map_apu_port(ptr: u16) -> Option<ApuPort> {
     }
 }
 
+// APU cycles at which the frame counter clocks the quarter frame, half frame, quarter frame,
+// 4-step frame end and 5-step frame end.
+const NTSC_FRAME_COUNTER_STEPS: [u16; 5] = [3728, 7456, 11185, 14914, 18640];
+const PAL_FRAME_COUNTER_STEPS: [u16; 5] = [4156, 8313, 12469, 16626, 20782];
+
 // ttps://wiki.nesdev.com/w/index.php/APU_Frame_Counter
 struct FrameCounter {
     step: u16,
     interrupt_inhibit: bool,
     mode: bool, // false=4-step, true=5-step
+    steps: [u16; 5],
 }
 
 impl FrameCounter {
@@ -87,29 +93,32 @@ impl FrameCounter {
             step: 0,
             interrupt_inhibit: false,
             mode: false,
+            steps: NTSC_FRAME_COUNTER_STEPS,
         }
     }
     pub fn is_quarter_frame_edge(&self) -> bool {
+        let [quarter1, half, quarter3, four_step_end, five_step_end] = self.steps;
         match (self.mode, self.step) {
-            (_, 3728) => true,
-            (_, 7456) => true,
-            (_, 11185) => true,
-            (false, 14914) => true,
-            (true, 18640) => true,
+            (_, x) if x == quarter1 => true,
+            (_, x) if x == half => true,
+            (_, x) if x == quarter3 => true,
+            (false, x) if x == four_step_end => true,
+            (true, x) if x == five_step_end => true,
             _ => false,
         }
     }
     pub fn is_half_frame_edge(&self) -> bool {
+        let [_, half, _, four_step_end, five_step_end] = self.steps;
         match (self.mode, self.step) {
-            (_, 7456) => true,
-            (false, 14914) => true,
-            (true, 18640) => true,
+            (_, x) if x == half => true,
+            (false, x) if x == four_step_end => true,
+            (true, x) if x == five_step_end => true,
             _ => false,
         }
     }
     pub fn is_frame_edge(&self) -> bool {
         match (self.mode, self.step) {
-            (false, 14914) => true,
+            (false, x) if x == self.steps[3] => true,
             _ => false,
         }
     }
@@ -122,7 +131,7 @@ impl FrameCounter {
 impl Clocked for FrameCounter {
     fn clock(&mut self) {
         self.step += 1;
-        let cap = ternary(self.mode, 18641, 14915);
+        let cap = ternary(self.mode, self.steps[4], self.steps[3]) + 1;
         // self.step %= cap;
         if self.step >= cap {
             self.step -= cap;
@@ -194,7 +203,7 @@ impl Apu {
             cycle: 0,
             is_recording: true,
             samples: Vec::new(),
-            sample_rate: CLOCKS_PER_FRAME / SAMPLES_PER_FRAME,
+            sample_rate: Region::Ntsc.cpu_clock_rate() / AUDIO_SAMPLE_RATE,
             sample_timer: 0.0,
             frame_counter: FrameCounter::new(),
             pulse1: Pulse::new(false),
@@ -204,6 +213,18 @@ impl Apu {
             dmc: Dmc::new(),
         }
     }
+    // Dendy clones keep the NTSC APU tables, but run them off their own CPU clock.
+    pub fn set_region(&mut self, region: Region) {
+        self.sample_rate = region.cpu_clock_rate() / AUDIO_SAMPLE_RATE;
+        let is_pal = region == Region::Pal;
+        self.frame_counter.steps =
+            ternary(is_pal, PAL_FRAME_COUNTER_STEPS, NTSC_FRAME_COUNTER_STEPS);
+        self.noise.period_table = ternary(
+            is_pal,
+            PAL_NOISE_PERIOD_LOOKUP_TABLE,
+            NOISE_PERIOD_LOOKUP_TABLE,
+        );
+    }
     pub fn reset(&mut self) {
         // TODO - Implement reset
         // self.pulse1.reset();
@@ -713,6 +734,9 @@ impl Clocked for Pulse {
 const NOISE_PERIOD_LOOKUP_TABLE: [u16; 16] = [
     4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
 ];
+const PAL_NOISE_PERIOD_LOOKUP_TABLE: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
 
 struct Noise {
     envelope: Envelope,
@@ -721,6 +745,7 @@ struct Noise {
     period: u16,
     feedback: u16,
     timer: u16,
+    period_table: [u16; 16],
 }
 
 impl Clocked for Noise {
@@ -745,6 +770,7 @@ impl Noise {
             period: 0,
             feedback: 1,
             timer: 0,
+            period_table: NOISE_PERIOD_LOOKUP_TABLE,
         }
     }
     fn sample(&self) -> f64 {
@@ -766,7 +792,7 @@ impl Noise {
     }
     pub fn set_period(&mut self, v: u8) {
         self.mode = (v & 0x8) > 0;
-        self.period = NOISE_PERIOD_LOOKUP_TABLE[(v & 0xf) as usize];
+        self.period = self.period_table[(v & 0xf) as usize];
     }
     pub fn set_length(&mut self, v: u8) {
         self.length_counter.set_load(v >> 3);
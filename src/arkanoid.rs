@@ -0,0 +1,115 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use core::cell::Cell;
+use std::io::Read;
+use std::io::Write;
+
+use crate::common::get_bit;
+use crate::joystick::{DATA_LINE_D3, DATA_LINE_D4};
+use crate::mapper::AddressSpace;
+use crate::serialization::Savable;
+
+// https://wiki.nesdev.com/w/index.php/Arkanoid_controller
+// The range of knob positions Arkanoid expects, from left to right
+pub const ARKANOID_MIN_POSITION: u8 = 98;
+pub const ARKANOID_MAX_POSITION: u8 = 242;
+
+// The NES Vaus controller. A strobe latches the knob's position, which is then read MSB first
+// and inverted on D4. The button is on D3.
+pub struct Arkanoid {
+    position: u8,
+    is_button_pressed: bool,
+    shift_register: Cell<u8>,
+    strobe_active: bool,
+}
+
+impl Savable for Arkanoid {
+    fn save(&self, fh: &mut dyn Write) {
+        self.shift_register.get().save(fh);
+        self.strobe_active.save(fh);
+    }
+    fn load(&mut self, fh: &mut dyn Read) {
+        let mut shift_register = 0;
+        shift_register.load(fh);
+        self.shift_register.set(shift_register);
+        self.strobe_active.load(fh);
+    }
+}
+
+impl Default for Arkanoid {
+    fn default() -> Self {
+        Arkanoid::new()
+    }
+}
+
+impl Arkanoid {
+    pub fn new() -> Arkanoid {
+        Arkanoid {
+            position: ARKANOID_MIN_POSITION,
+            is_button_pressed: false,
+            shift_register: Cell::new(0),
+            strobe_active: false,
+        }
+    }
+    pub fn set_position(&mut self, position: u8) {
+        self.position = position;
+    }
+    // 0.0 is the far left, and 1.0 the far right
+    pub fn set_position_fraction(&mut self, fraction: f64) {
+        let range = (ARKANOID_MAX_POSITION - ARKANOID_MIN_POSITION) as f64;
+        let offset = (fraction.clamp(0.0, 1.0) * range).round() as u8;
+        self.position = ARKANOID_MIN_POSITION + offset;
+    }
+    pub fn set_button(&mut self, is_button_pressed: bool) {
+        self.is_button_pressed = is_button_pressed;
+    }
+}
+
+impl AddressSpace for Arkanoid {
+    fn peek(&self, _ptr: u16) -> u8 {
+        if self.strobe_active {
+            self.shift_register.set(self.position);
+        }
+        let register = self.shift_register.get();
+        self.shift_register.set(register << 1);
+        let mut data = 0;
+        if get_bit(register, 7) == 0 {
+            data |= DATA_LINE_D4;
+        }
+        if self.is_button_pressed {
+            data |= DATA_LINE_D3;
+        }
+        data
+    }
+    fn poke(&mut self, _ptr: u16, v: u8) {
+        self.strobe_active = get_bit(v, 0) > 0;
+        if self.strobe_active {
+            self.shift_register.set(self.position);
+        }
+    }
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arkanoid() {
+        let mut arkanoid = Arkanoid::new();
+        arkanoid.set_position(0b1010_0110);
+        arkanoid.set_button(true);
+        arkanoid.poke(0x4016, 1);
+        arkanoid.poke(0x4016, 0);
+        let reads: Vec<u8> = (0..8).map(|_| arkanoid.peek(0x4017)).collect();
+        let position = reads.iter().fold(0, |position, data| {
+            position << 1 | (data & DATA_LINE_D4 == 0) as u8
+        });
+        assert_eq!(position, 0b1010_0110);
+        assert!(reads.iter().all(|data| data & DATA_LINE_D3 != 0));
+
+        arkanoid.set_position_fraction(1.0);
+        assert_eq!(arkanoid.position, ARKANOID_MAX_POSITION);
+        arkanoid.set_position_fraction(-1.0);
+        assert_eq!(arkanoid.position, ARKANOID_MIN_POSITION);
+    }
+}
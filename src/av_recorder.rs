@@ -0,0 +1,214 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use crate::apu::AUDIO_SAMPLE_RATE;
+use crate::ppu::{PixelFormat, Ppu, RENDER_HEIGHT, RENDER_SIZE, RENDER_WIDTH};
+use crate::region::Region;
+
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+// Records every frame and audio sample into `<basename>.y4m` and `<basename>.wav`. Frames are
+// stored as uncompressed 4:4:4 YCbCr, and samples as the APU's 32-bit floats.
+pub struct AvRecorder {
+    video: Y4mWriter<BufWriter<File>>,
+    audio: WavWriter<BufWriter<File>>,
+    frame: Vec<u8>,
+}
+
+impl AvRecorder {
+    pub fn create(basename: &str, region: Region) -> Result<AvRecorder, io::Error> {
+        let video = BufWriter::new(File::create(format!("{}.y4m", basename))?);
+        let audio = BufWriter::new(File::create(format!("{}.wav", basename))?);
+        Ok(AvRecorder {
+            video: Y4mWriter::new(video, RENDER_WIDTH, RENDER_HEIGHT, region.frame_rate())?,
+            audio: WavWriter::new(audio, AUDIO_SAMPLE_RATE as u32)?,
+            frame: vec![0; RENDER_SIZE],
+        })
+    }
+    // Call once per frame, after Nes::run_frame and before the APU's samples are cleared.
+    pub fn record_frame(&mut self, ppu: &Ppu, samples: &[f32]) -> Result<(), io::Error> {
+        ppu.render_into(&mut self.frame, PixelFormat::Rgb24, None);
+        self.video.write_frame(&self.frame)?;
+        self.audio.write_samples(samples)
+    }
+    pub fn num_frames(&self) -> u64 {
+        self.video.num_frames
+    }
+    // The WAV header holds the data size, so it isn't valid until the recording is finished.
+    pub fn finish(self) -> Result<(), io::Error> {
+        self.video.finish()?;
+        self.audio.finish()?;
+        Ok(())
+    }
+}
+
+// https://wiki.multimedia.cx/index.php/YUV4MPEG2
+pub struct Y4mWriter<W: Write> {
+    out: W,
+    width: usize,
+    height: usize,
+    num_frames: u64,
+    planes: Vec<u8>,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    pub fn new(
+        mut out: W,
+        width: usize,
+        height: usize,
+        (numerator, denominator): (u64, u64),
+    ) -> Result<Y4mWriter<W>, io::Error> {
+        writeln!(
+            out,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444 XCOLORRANGE=FULL",
+            width, height, numerator, denominator
+        )?;
+        Ok(Y4mWriter {
+            out,
+            width,
+            height,
+            num_frames: 0,
+            planes: vec![0; width * height * 3],
+        })
+    }
+    // `rgb` is an RGB24 image without padding between rows
+    pub fn write_frame(&mut self, rgb: &[u8]) -> Result<(), io::Error> {
+        let num_pixels = self.width * self.height;
+        assert!(rgb.len() >= num_pixels * 3, "Frame is too small");
+        for (i, pixel) in rgb[..num_pixels * 3].chunks(3).enumerate() {
+            let (y, cb, cr) = rgb_to_ycbcr(pixel[0], pixel[1], pixel[2]);
+            self.planes[i] = y;
+            self.planes[num_pixels + i] = cb;
+            self.planes[2 * num_pixels + i] = cr;
+        }
+        self.out.write_all(b"FRAME\n")?;
+        self.out.write_all(&self.planes)?;
+        self.num_frames += 1;
+        Ok(())
+    }
+    pub fn finish(mut self) -> Result<W, io::Error> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// Full-range BT.601
+fn rgb_to_ycbcr(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let (r, g, b) = (r as f64, g as f64, b as f64);
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let cb = 128.0 - 0.168_736 * r - 0.331_264 * g + 0.5 * b;
+    let cr = 128.0 + 0.5 * r - 0.418_688 * g - 0.081_312 * b;
+    let channel = |x: f64| x.round().clamp(0.0, 255.0) as u8;
+    (channel(y), channel(cb), channel(cr))
+}
+
+// Mono 32-bit float samples.
+// http://www-mmsp.ece.mcgill.ca/Documents/AudioFormats/WAVE/WAVE.html
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    num_samples: u32,
+}
+
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAV_HEADER_SIZE: u32 = 58;
+const RIFF_SIZE_OFFSET: u64 = 4;
+const FACT_SAMPLES_OFFSET: u64 = 46;
+const DATA_SIZE_OFFSET: u64 = 54;
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> Result<WavWriter<W>, io::Error> {
+        let bytes_per_sample: u16 = 4;
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVE")?;
+        out.write_all(b"fmt ")?;
+        out.write_all(&18u32.to_le_bytes())?;
+        out.write_all(&WAVE_FORMAT_IEEE_FLOAT.to_le_bytes())?;
+        // Channels
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * bytes_per_sample as u32).to_le_bytes())?;
+        out.write_all(&bytes_per_sample.to_le_bytes())?;
+        out.write_all(&(bytes_per_sample * 8).to_le_bytes())?;
+        // No format extension
+        out.write_all(&0u16.to_le_bytes())?;
+        // Formats other than integer PCM need a sample count.
+        out.write_all(b"fact")?;
+        out.write_all(&4u32.to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter {
+            out,
+            num_samples: 0,
+        })
+    }
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<(), io::Error> {
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.num_samples += samples.len() as u32;
+        Ok(())
+    }
+    pub fn finish(mut self) -> Result<W, io::Error> {
+        let data_size = self.num_samples * 4;
+        self.out.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        self.out
+            .write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(FACT_SAMPLES_OFFSET))?;
+        self.out.write_all(&self.num_samples.to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+        self.out.write_all(&data_size.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_y4m_writer() {
+        let mut writer = Y4mWriter::new(Vec::new(), 2, 1, Region::Ntsc.frame_rate()).unwrap();
+        writer.write_frame(&[255, 255, 255, 255, 0, 0]).unwrap();
+        writer.write_frame(&[0; 6]).unwrap();
+        let bytes = writer.finish().unwrap();
+        let header = b"YUV4MPEG2 W2 H1 F39375000:655171 Ip A1:1 C444 XCOLORRANGE=FULL\n";
+        assert_eq!(&bytes[..header.len()], &header[..]);
+        let frame = &bytes[header.len()..];
+        // Y plane, then Cb, then Cr
+        assert_eq!(frame[..12], *b"FRAME\n\xFF\x4C\x80\x55\x80\xFF");
+        assert_eq!(frame.len(), 2 * (6 + 6));
+    }
+    #[test]
+    fn test_wav_writer() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        writer.write_samples(&[0.5, -0.25]).unwrap();
+        writer.write_samples(&[1.0]).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+        assert_eq!(bytes.len(), WAV_HEADER_SIZE as usize + 12);
+        let read_u32 =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        assert_eq!(read_u32(4), bytes.len() as u32 - 8);
+        assert_eq!(read_u32(FACT_SAMPLES_OFFSET as usize), 3);
+        assert_eq!(&bytes[50..54], b"data");
+        assert_eq!(read_u32(DATA_SIZE_OFFSET as usize), 12);
+        assert_eq!(bytes[58..62], 0.5f32.to_le_bytes());
+    }
+    #[test]
+    fn test_frame_rate() {
+        for region in [Region::Ntsc, Region::Pal, Region::Dendy].iter() {
+            let (numerator, denominator) = region.frame_rate();
+            let fps = numerator as f64 / denominator as f64;
+            assert!(
+                (fps - region.frames_per_second()).abs() < 1e-9,
+                "{:?}",
+                region
+            );
+        }
+    }
+}
@@ -0,0 +1,456 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::str::FromStr;
+
+// Key, button and axis names are SDL's, so frontends resolve them:
+// https://wiki.libsdl.org/SDL_Keycode and https://wiki.libsdl.org/SDL_GameControllerGetStringForButton
+#[derive(Clone, Debug, PartialEq)]
+pub enum BoundInput {
+    Key(String),
+    // A button on the controller being bound, or on any controller for hotkeys
+    Button(String),
+    // A stick or trigger pushed at least halfway in the positive(true) or negative direction
+    Axis(String, bool),
+}
+
+impl FromStr for BoundInput {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, name) = match s.find(':') {
+            Some(i) => (&s[..i], s[i + 1..].trim()),
+            None => return Err(format!("Expected key:, button: or axis: in {}", s)),
+        };
+        if name.is_empty() {
+            return Err(format!("Missing name in {}", s));
+        }
+        match kind.trim() {
+            "key" => Ok(BoundInput::Key(name.to_string())),
+            "button" => Ok(BoundInput::Button(name.to_string())),
+            "axis" if name.ends_with('+') => Ok(BoundInput::Axis(trim_sign(name), true)),
+            "axis" if name.ends_with('-') => Ok(BoundInput::Axis(trim_sign(name), false)),
+            "axis" => Err(format!(
+                "Axes need a direction, like {}+ or {}-",
+                name, name
+            )),
+            _ => Err(format!("Unknown input kind {}", kind)),
+        }
+    }
+}
+
+fn trim_sign(name: &str) -> String {
+    name[..name.len() - 1].trim().to_string()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Hotkey {
+    SaveState,
+    LoadState,
+    // Restarts from the recording's start point and plays it back
+    PlayRecording,
+    // Sets the recording's start point here
+    StartRecording,
+    Turbo,
+    Pause,
+    // Breaks a command-line debugger
+    Debugger,
+    ToggleBackground,
+    ToggleSprites,
+    // A PNG of the frame as displayed, or the native frame when Shift is held
+    Screenshot,
+    ToggleAvRecording,
+    ToggleGif,
+}
+
+pub const HOTKEYS: [Hotkey; 12] = [
+    Hotkey::SaveState,
+    Hotkey::LoadState,
+    Hotkey::PlayRecording,
+    Hotkey::StartRecording,
+    Hotkey::Turbo,
+    Hotkey::Pause,
+    Hotkey::Debugger,
+    Hotkey::ToggleBackground,
+    Hotkey::ToggleSprites,
+    Hotkey::Screenshot,
+    Hotkey::ToggleAvRecording,
+    Hotkey::ToggleGif,
+];
+
+impl FromStr for Hotkey {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "save_state" => Ok(Hotkey::SaveState),
+            "load_state" => Ok(Hotkey::LoadState),
+            "play_recording" => Ok(Hotkey::PlayRecording),
+            "start_recording" => Ok(Hotkey::StartRecording),
+            "turbo" => Ok(Hotkey::Turbo),
+            "pause" => Ok(Hotkey::Pause),
+            "debugger" => Ok(Hotkey::Debugger),
+            "toggle_background" => Ok(Hotkey::ToggleBackground),
+            "toggle_sprites" => Ok(Hotkey::ToggleSprites),
+            "screenshot" => Ok(Hotkey::Screenshot),
+            "toggle_av_recording" => Ok(Hotkey::ToggleAvRecording),
+            "toggle_gif" => Ok(Hotkey::ToggleGif),
+            _ => Err(format!("Unknown hotkey {}", s)),
+        }
+    }
+}
+
+// In joystick bit order
+pub const JOYSTICK_BUTTON_NAMES: [&str; 8] =
+    ["a", "b", "select", "start", "up", "down", "left", "right"];
+// Turbo A and B, in joystick bit order
+pub const TURBO_BUTTON_NAMES: [&str; 2] = ["turbo_a", "turbo_b"];
+pub const NUM_CONTROLLERS: usize = 4;
+// Frames pressed, then frames released
+pub const DEFAULT_TURBO_RATE: u32 = 2;
+pub const NUM_POWER_PAD_BUTTONS: usize = 12;
+
+const DEFAULT_KEYS: [&str; 8] = [
+    "K",
+    "J",
+    "Right Shift",
+    "Return",
+    "Up",
+    "Down",
+    "Left",
+    "Right",
+];
+const DEFAULT_TURBO_KEYS: [&str; 2] = ["I", "U"];
+const DEFAULT_TURBO_PAD_BUTTONS: [&str; 2] = ["x", "y"];
+const DEFAULT_PAD_BUTTONS: [&str; 8] = [
+    "a", "b", "back", "start", "dpup", "dpdown", "dpleft", "dpright",
+];
+const DEFAULT_PAD_AXES: [Option<(&str, bool)>; 8] = [
+    None,
+    None,
+    None,
+    None,
+    Some(("lefty", false)),
+    Some(("lefty", true)),
+    Some(("leftx", false)),
+    Some(("leftx", true)),
+];
+// The mat's rows of 1-4, 5-8 and 9-12, laid out on the keyboard
+const DEFAULT_POWER_PAD_KEYS: [&str; NUM_POWER_PAD_BUTTONS] =
+    ["Q", "W", "E", "R", "A", "S", "D", "F", "Z", "X", "C", "V"];
+const DEFAULT_HOTKEYS: [&str; 12] = [
+    "F5", "F6", "F7", "F8", "Tab", "P", "Pause", "F1", "F2", "F9", "F10", "F11",
+];
+
+// What drives each controller's buttons, the Power Pads' buttons, and the hotkeys
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bindings {
+    // Indexed by controller, then joystick button bit
+    pub controllers: [[Vec<BoundInput>; 8]; NUM_CONTROLLERS],
+    // Indexed by controller, then turbo button
+    pub turbo: [[Vec<BoundInput>; 2]; NUM_CONTROLLERS],
+    pub turbo_rates: [u32; NUM_CONTROLLERS],
+    // Button n is at n-1
+    pub power_pad: [Vec<BoundInput>; NUM_POWER_PAD_BUTTONS],
+    // In HOTKEYS order
+    pub hotkeys: [Vec<BoundInput>; 12],
+}
+
+// Controller 1 is on the keyboard too. Every controller has its own game controller's d-pad and
+// left stick.
+impl Default for Bindings {
+    fn default() -> Self {
+        let key = |name: &str| BoundInput::Key(name.to_string());
+        let mut bindings = Bindings {
+            controllers: Default::default(),
+            turbo: Default::default(),
+            turbo_rates: [DEFAULT_TURBO_RATE; NUM_CONTROLLERS],
+            power_pad: Default::default(),
+            hotkeys: Default::default(),
+        };
+        for (controller_id, controller) in bindings.controllers.iter_mut().enumerate() {
+            for (i, inputs) in controller.iter_mut().enumerate() {
+                if controller_id == 0 {
+                    inputs.push(key(DEFAULT_KEYS[i]));
+                }
+                inputs.push(BoundInput::Button(DEFAULT_PAD_BUTTONS[i].to_string()));
+                if let Some((axis, is_positive)) = DEFAULT_PAD_AXES[i] {
+                    inputs.push(BoundInput::Axis(axis.to_string(), is_positive));
+                }
+            }
+            for (i, inputs) in bindings.turbo[controller_id].iter_mut().enumerate() {
+                if controller_id == 0 {
+                    inputs.push(key(DEFAULT_TURBO_KEYS[i]));
+                }
+                inputs.push(BoundInput::Button(DEFAULT_TURBO_PAD_BUTTONS[i].to_string()));
+            }
+        }
+        for (inputs, name) in bindings
+            .power_pad
+            .iter_mut()
+            .zip(DEFAULT_POWER_PAD_KEYS.iter())
+        {
+            inputs.push(key(name));
+        }
+        for (inputs, name) in bindings.hotkeys.iter_mut().zip(DEFAULT_HOTKEYS.iter()) {
+            inputs.push(key(name));
+        }
+        bindings
+    }
+}
+
+impl Bindings {
+    pub fn hotkey(&self, hotkey: Hotkey) -> &[BoundInput] {
+        let i = HOTKEYS.iter().position(|x| *x == hotkey).unwrap();
+        &self.hotkeys[i]
+    }
+}
+
+// `is_known` checks key, button and axis names against the frontend's
+pub fn read_bindings(
+    filename: String,
+    is_known: &dyn Fn(&BoundInput) -> bool,
+) -> Result<Bindings, io::Error> {
+    let mut file = File::open(filename)?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    parse_bindings(&text, is_known)
+}
+
+// A small subset of TOML: [controller1]-[controller4], [power_pad] and [hotkeys] sections of
+// `name = "input"` or `name = ["input", ...]` lines. Each line replaces the defaults for that
+// name, and an empty list unbinds it. Controllers also take `turbo_rate = <frames>`.
+pub fn parse_bindings(
+    text: &str,
+    is_known: &dyn Fn(&BoundInput) -> bool,
+) -> Result<Bindings, io::Error> {
+    let mut bindings = Bindings::default();
+    let mut section = None;
+    for (i, line) in text.lines().enumerate() {
+        let invalid = |e: String| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Line {}: {}", i + 1, e))
+        };
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = Some(line[1..line.len() - 1].trim().to_string());
+            continue;
+        }
+        let (name, value) = match line.find('=') {
+            Some(j) => (line[..j].trim(), line[j + 1..].trim()),
+            None => return Err(invalid(format!("Expected name = value in {}", line))),
+        };
+        let section = match section.as_ref() {
+            Some(section) => section.as_str(),
+            None => return Err(invalid(format!("{} is outside of a section", name))),
+        };
+        if name == "turbo_rate" {
+            let controller_id = controller_id(section).map_err(invalid)?;
+            bindings.turbo_rates[controller_id] = match value.parse::<u32>() {
+                Ok(rate) if rate > 0 => rate,
+                _ => {
+                    return Err(invalid(format!(
+                        "Expected a turbo rate in frames, found {}",
+                        value
+                    )))
+                }
+            };
+            continue;
+        }
+        let inputs = parse_inputs(value).map_err(invalid)?;
+        if let Some(input) = inputs.iter().find(|input| !is_known(input)) {
+            return Err(invalid(match input {
+                BoundInput::Key(name) => format!("Unknown key {}", name),
+                BoundInput::Button(name) => format!("Unknown button {}", name),
+                BoundInput::Axis(name, _) => format!("Unknown axis {}", name),
+            }));
+        }
+        // Hotkeys fire once per press, which axes don't have
+        if section == "hotkeys" && inputs.iter().any(|x| matches!(x, BoundInput::Axis(..))) {
+            return Err(invalid(format!(
+                "Hotkey {} can't be bound to an axis",
+                name
+            )));
+        }
+        let slot = binding_slot(&mut bindings, section, name).map_err(invalid)?;
+        *slot = inputs;
+    }
+    Ok(bindings)
+}
+
+fn binding_slot<'a>(
+    bindings: &'a mut Bindings,
+    section: &str,
+    name: &str,
+) -> Result<&'a mut Vec<BoundInput>, String> {
+    if section == "hotkeys" {
+        let hotkey = Hotkey::from_str(name)?;
+        let i = HOTKEYS.iter().position(|x| *x == hotkey).unwrap();
+        return Ok(&mut bindings.hotkeys[i]);
+    }
+    if section == "power_pad" {
+        return match name.trim_start_matches("button").parse::<usize>() {
+            Ok(n) if (1..=NUM_POWER_PAD_BUTTONS).contains(&n) => Ok(&mut bindings.power_pad[n - 1]),
+            _ => Err(format!("Unknown Power Pad button {}", name)),
+        };
+    }
+    let controller_id = controller_id(section)?;
+    if let Some(button) = TURBO_BUTTON_NAMES.iter().position(|x| *x == name) {
+        return Ok(&mut bindings.turbo[controller_id][button]);
+    }
+    match JOYSTICK_BUTTON_NAMES.iter().position(|x| *x == name) {
+        Some(button) => Ok(&mut bindings.controllers[controller_id][button]),
+        None => Err(format!("Unknown button {}", name)),
+    }
+}
+
+// 0-3, from the section names [controller1]-[controller4]
+fn controller_id(section: &str) -> Result<usize, String> {
+    match section.trim_start_matches("controller").parse::<usize>() {
+        Ok(n) if section.starts_with("controller") && (1..=NUM_CONTROLLERS).contains(&n) => {
+            Ok(n - 1)
+        }
+        _ => Err(format!("Unknown section {}", section)),
+    }
+}
+
+fn parse_inputs(value: &str) -> Result<Vec<BoundInput>, String> {
+    let strings = if value.starts_with('[') && value.ends_with(']') {
+        let list = value[1..value.len() - 1].trim();
+        if list.is_empty() {
+            return Ok(Vec::new());
+        }
+        list.split(',')
+            .map(|x| parse_string(x.trim()))
+            .collect::<Result<Vec<&str>, String>>()?
+    } else {
+        vec![parse_string(value)?]
+    };
+    strings.into_iter().map(BoundInput::from_str).collect()
+}
+
+fn parse_string(value: &str) -> Result<&str, String> {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        Ok(&value[1..value.len() - 1])
+    } else {
+        Err(format!("Expected a quoted string, found {}", value))
+    }
+}
+
+// Comments start with # outside of a string
+fn strip_comment(line: &str) -> &str {
+    let mut is_in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => is_in_string = !is_in_string,
+            '#' if !is_in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+mod tests {
+    use super::*;
+
+    fn is_known(input: &BoundInput) -> bool {
+        match input {
+            BoundInput::Key(name) | BoundInput::Button(name) | BoundInput::Axis(name, _) => {
+                name != "unknown"
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_bindings() {
+        let bindings = parse_bindings(
+            r#"
+            # Player 2 on the keyboard
+            [controller2]
+            a = ["key:Keypad 0", "button:x"] # Comment
+            left = "axis:rightx-"
+            select = []
+            turbo_b = "key:Keypad 1"
+            turbo_rate = 3
+
+            [power_pad]
+            button12 = "key:#"
+
+            [hotkeys]
+            save_state = "button:guide"
+            screenshot = ["key:F12", "button:back"]
+            "#,
+            &is_known,
+        )
+        .unwrap();
+        assert_eq!(
+            bindings.controllers[1][0],
+            vec![
+                BoundInput::Key("Keypad 0".to_string()),
+                BoundInput::Button("x".to_string())
+            ]
+        );
+        assert_eq!(
+            bindings.controllers[1][6],
+            vec![BoundInput::Axis("rightx".to_string(), false)]
+        );
+        assert_eq!(bindings.controllers[1][2], vec![]);
+        assert_eq!(
+            bindings.controllers[1][1],
+            Bindings::default().controllers[1][1]
+        );
+        assert_eq!(
+            bindings.turbo[1][1],
+            vec![BoundInput::Key("Keypad 1".to_string())]
+        );
+        assert_eq!(bindings.turbo_rates, [2, 3, 2, 2]);
+        assert_eq!(
+            bindings.power_pad[11],
+            vec![BoundInput::Key("#".to_string())]
+        );
+        assert_eq!(
+            bindings.hotkey(Hotkey::SaveState),
+            &[BoundInput::Button("guide".to_string())]
+        );
+        assert_eq!(
+            bindings.hotkey(Hotkey::Turbo),
+            &[BoundInput::Key("Tab".to_string())]
+        );
+        assert_eq!(
+            bindings.hotkey(Hotkey::Screenshot),
+            &[
+                BoundInput::Key("F12".to_string()),
+                BoundInput::Button("back".to_string())
+            ]
+        );
+        assert_eq!(
+            bindings.hotkey(Hotkey::ToggleGif),
+            &[BoundInput::Key("F11".to_string())]
+        );
+    }
+    #[test]
+    fn test_invalid_bindings() {
+        for text in &[
+            "a = \"key:X\"",
+            "[controller5]\na = \"key:X\"",
+            "[controller1]\nturbo = \"key:X\"",
+            "[controller1]\na = key:X",
+            "[controller1]\na = \"joystick:X\"",
+            "[controller1]\nup = \"axis:lefty\"",
+            "[hotkeys]\nrewind = \"key:R\"",
+            "[controller1]\nturbo_rate = 0",
+            "[hotkeys]\nturbo_rate = 2",
+            "[hotkeys]\npause = \"axis:lefty+\"",
+            "[controller1]\na = \"key:unknown\"",
+            "[controller1]\na = [\"button:a\", \"button:unknown\"]",
+            "[controller1]\nup = \"axis:unknown-\"",
+        ] {
+            assert!(parse_bindings(text, &is_known).is_err(), "{}", text);
+        }
+        let e = parse_bindings("[controller1]\n\nb = \"key:unknown\"", &is_known).unwrap_err();
+        assert_eq!(e.to_string(), "Line 3: Unknown key unknown");
+    }
+}
@@ -109,6 +109,39 @@ impl C6502 {
     pub fn initialize(&mut self) {
         self.pc = self.peek16(ADDRESS_RESET);
     }
+    // https://wiki.nesdev.com/w/index.php/CPU_power_up_state#After_reset
+    pub fn reset(&mut self) {
+        self.sp = self.sp.wrapping_sub(3);
+        self.interruptd = true;
+        self.pc = self.peek16(ADDRESS_RESET);
+        self.clocks_to_pause = 7;
+    }
+    // nestest's "automation mode" skips the reset vector and starts at $C000 with interrupts disabled.
+    pub fn initialize_automation(&mut self) {
+        self.pc = ADDRESS_TEST_PROGRAM;
+        self.interruptd = true;
+    }
+    pub fn registers(&self) -> C6502Registers {
+        C6502Registers {
+            pc: self.pc,
+            acc: self.acc,
+            x: self.x,
+            y: self.y,
+            status: self.status_register_byte(true),
+            sp: self.sp,
+        }
+    }
+}
+
+// A snapshot of the programmer-visible registers, in the order nestest.log prints them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct C6502Registers {
+    pub pc: u16,
+    pub acc: u8,
+    pub x: u8,
+    pub y: u8,
+    pub status: u8,
+    pub sp: u8,
 }
 
 struct C6502Debugger {
@@ -624,7 +657,10 @@ impl C6502 {
         self.debugger.on_nmi();
         self.push_stack16(pc);
         self.push_stack(status);
+        self.interruptd = true;
         self.pc = self.peek16(ADDRESS_NMI);
+        // Pushing the return address and reading the vector takes 7 cycles.
+        self.clocks_to_pause += 7;
     }
     pub fn irq(&mut self) {
         if self.interruptd {
@@ -0,0 +1,232 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use core::cell::Cell;
+use std::io::Read;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::common::get_bit;
+use crate::joystick::{Joystick, DATA_LINE_D0, DATA_LINE_D1};
+use crate::mapper::AddressSpace;
+use crate::serialization::Savable;
+
+// Ways to connect controllers 3 and 4
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FourPlayerAdapter {
+    // NES Four Score or Satellite, in both controller ports
+    FourScore,
+    // Famicom controllers in the expansion port, reported on D1
+    Famicom,
+}
+
+impl FromStr for FourPlayerAdapter {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "four-score" | "fourscore" | "satellite" => Ok(FourPlayerAdapter::FourScore),
+            "famicom" => Ok(FourPlayerAdapter::Famicom),
+            _ => Err(format!("Unknown four player adapter {}", s)),
+        }
+    }
+}
+
+// Savestates record whether an adapter was connected, since it changes how much state the
+// controller ports have.
+impl Savable for Option<FourPlayerAdapter> {
+    fn save(&self, fh: &mut dyn Write) {
+        let x = match self {
+            None => 0u8,
+            Some(FourPlayerAdapter::FourScore) => 1,
+            Some(FourPlayerAdapter::Famicom) => 2,
+        };
+        x.save(fh);
+    }
+    fn load(&mut self, fh: &mut dyn Read) {
+        let mut x = 0u8;
+        x.load(fh);
+        *self = match x {
+            0 => None,
+            1 => Some(FourPlayerAdapter::FourScore),
+            2 => Some(FourPlayerAdapter::Famicom),
+            _ => panic!("Unknown four player adapter {}", x),
+        };
+    }
+}
+
+impl FourPlayerAdapter {
+    // The device for port 0, given controllers 1 and 3, or for port 1 given controllers 2 and 4
+    pub fn connect(
+        self,
+        port: usize,
+        first: Box<Joystick>,
+        second: Box<Joystick>,
+    ) -> Box<dyn AddressSpace> {
+        match self {
+            FourPlayerAdapter::FourScore => Box::new(FourScore::new(port, first, second)),
+            FourPlayerAdapter::Famicom => Box::new(FamicomExpansion::new(first, second)),
+        }
+    }
+}
+
+// https://wiki.nesdev.com/w/index.php/Four_player_adapters
+// Each port reads 24 bits: 8 buttons of its first controller, 8 of its second, then a signature
+// that tells games the adapter is there.
+const FOUR_SCORE_SIGNATURES: [u8; 2] = [0b0001_0000, 0b0010_0000];
+const FOUR_SCORE_REPORT_BITS: u8 = 24;
+
+pub struct FourScore {
+    first: Box<Joystick>,
+    second: Box<Joystick>,
+    signature: u8,
+    num_reads: Cell<u8>,
+    strobe_active: bool,
+}
+
+impl Savable for FourScore {
+    fn save(&self, fh: &mut dyn Write) {
+        self.first.save(fh);
+        self.second.save(fh);
+        self.num_reads.get().save(fh);
+        self.strobe_active.save(fh);
+    }
+    fn load(&mut self, fh: &mut dyn Read) {
+        self.first.load(fh);
+        self.second.load(fh);
+        let mut num_reads = 0;
+        num_reads.load(fh);
+        self.num_reads.set(num_reads);
+        self.strobe_active.load(fh);
+    }
+}
+
+impl FourScore {
+    // `port` is 0 for controllers 1 and 3, or 1 for controllers 2 and 4
+    pub fn new(port: usize, first: Box<Joystick>, second: Box<Joystick>) -> FourScore {
+        FourScore {
+            first,
+            second,
+            signature: FOUR_SCORE_SIGNATURES[port],
+            num_reads: Cell::new(0),
+            strobe_active: false,
+        }
+    }
+}
+
+impl AddressSpace for FourScore {
+    fn peek(&self, ptr: u16) -> u8 {
+        if self.strobe_active {
+            self.num_reads.set(0);
+        }
+        let num_reads = self.num_reads.get();
+        let bit = match num_reads {
+            0..=7 => self.first.peek(ptr),
+            8..=15 => self.second.peek(ptr),
+            16..=23 => get_bit(self.signature, num_reads - 16),
+            _ => 1,
+        };
+        if num_reads < FOUR_SCORE_REPORT_BITS {
+            self.num_reads.set(num_reads + 1);
+        }
+        bit & DATA_LINE_D0
+    }
+    fn poke(&mut self, ptr: u16, v: u8) {
+        self.strobe_active = get_bit(v, 0) > 0;
+        if self.strobe_active {
+            self.num_reads.set(0);
+        }
+        self.first.poke(ptr, v);
+        self.second.poke(ptr, v);
+    }
+}
+
+// https://wiki.nesdev.com/w/index.php/Standard_controller#Input_.28.244016.2F.244017_read.29
+// A controller in the Famicom expansion port reads alongside the port's own controller, on D1
+// instead of D0.
+pub struct FamicomExpansion {
+    controller: Box<Joystick>,
+    expansion: Box<Joystick>,
+}
+
+impl Savable for FamicomExpansion {
+    fn save(&self, fh: &mut dyn Write) {
+        self.controller.save(fh);
+        self.expansion.save(fh);
+    }
+    fn load(&mut self, fh: &mut dyn Read) {
+        self.controller.load(fh);
+        self.expansion.load(fh);
+    }
+}
+
+impl FamicomExpansion {
+    pub fn new(controller: Box<Joystick>, expansion: Box<Joystick>) -> FamicomExpansion {
+        FamicomExpansion {
+            controller,
+            expansion,
+        }
+    }
+}
+
+impl AddressSpace for FamicomExpansion {
+    fn peek(&self, ptr: u16) -> u8 {
+        let d0 = self.controller.peek(ptr) & DATA_LINE_D0;
+        let d1 = ((self.expansion.peek(ptr) & DATA_LINE_D0) << 1) & DATA_LINE_D1;
+        d0 | d1
+    }
+    fn poke(&mut self, ptr: u16, v: u8) {
+        self.controller.poke(ptr, v);
+        self.expansion.poke(ptr, v);
+    }
+}
+
+mod tests {
+    use super::*;
+
+    fn joystick(buttons: u8) -> Box<Joystick> {
+        let mut joystick = Box::new(Joystick::new());
+        joystick.set_buttons(buttons);
+        joystick
+    }
+    fn read_bits(port: &mut dyn AddressSpace, num_bits: usize) -> Vec<u8> {
+        port.poke(0x4016, 1);
+        port.poke(0x4016, 0);
+        (0..num_bits).map(|_| port.peek(0x4016)).collect()
+    }
+
+    #[test]
+    fn test_four_score() {
+        let adapter = FourPlayerAdapter::FourScore;
+        let mut port1 = adapter.connect(0, joystick(0x01), joystick(0x80));
+        let mut port2 = adapter.connect(1, joystick(0x02), joystick(0x40));
+        let bits = read_bits(&mut *port1, 25);
+        assert_eq!(bits[0], 1);
+        assert_eq!(bits[15], 1);
+        assert_eq!(bits.iter().filter(|&&bit| bit == 1).count(), 4);
+        // Signature, then 1s
+        assert_eq!(bits[16..25], [0, 0, 0, 0, 1, 0, 0, 0, 1]);
+        let bits = read_bits(&mut *port2, 24);
+        assert_eq!(bits[1], 1);
+        assert_eq!(bits[14], 1);
+        assert_eq!(bits[16..24], [0, 0, 0, 0, 0, 1, 0, 0]);
+    }
+    #[test]
+    fn test_famicom_expansion() {
+        let mut port1 = FourPlayerAdapter::Famicom.connect(0, joystick(0b01), joystick(0b10));
+        assert_eq!(read_bits(&mut *port1, 3), vec![0b01, 0b10, 0b00]);
+    }
+    #[test]
+    fn test_save_adapter() {
+        for adapter in &[
+            None,
+            Some(FourPlayerAdapter::FourScore),
+            Some(FourPlayerAdapter::Famicom),
+        ] {
+            let mut state = Vec::new();
+            adapter.save(&mut state);
+            let mut loaded = None;
+            loaded.load(&mut state.as_slice());
+            assert_eq!(loaded, *adapter);
+        }
+    }
+}
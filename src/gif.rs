@@ -0,0 +1,387 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use crate::nes::Nes;
+use crate::ppu::{ExtendedPalette, Ppu, RENDER_HEIGHT, RENDER_WIDTH, UNRENDER_SIZE};
+use crate::region::Region;
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GifParams {
+    pub frame_skip: u32, // Frames dropped after each captured one
+    pub scale: usize,
+}
+
+impl Default for GifParams {
+    fn default() -> GifParams {
+        // Browsers play delays under 2/100ths of a second slowly, so 60 FPS clips don't work.
+        GifParams {
+            frame_skip: 1,
+            scale: 1,
+        }
+    }
+}
+
+// Encodes frames straight from Ppu::display. The 64 system colors fit in GIF's 256-color limit,
+// so nothing is quantized. Frames with emphasis bits set carry their own color table.
+// https://www.w3.org/Graphics/GIF/spec-gif89a.txt
+pub struct GifEncoder<W: Write> {
+    out: W,
+    params: GifParams,
+    palette: ExtendedPalette,
+    frame_duration: f64, // In 1/100ths of a second
+    frames_seen: u32,
+    num_frames: u32,
+    elapsed: f64,
+    elapsed_written: u32,
+    // ExtendedPalette indices of the last frame written
+    previous: Option<Vec<u16>>,
+}
+
+const MIN_DELAY: u32 = 2;
+const MAX_CODE_SIZE: u8 = 12;
+const GLOBAL_COLORS: usize = 64;
+
+impl<W: Write> GifEncoder<W> {
+    pub fn new(
+        mut out: W,
+        palette: &ExtendedPalette,
+        region: Region,
+        params: GifParams,
+    ) -> Result<GifEncoder<W>, io::Error> {
+        assert!(params.scale > 0, "GIF scale must be at least 1");
+        let width = (RENDER_WIDTH * params.scale) as u16;
+        let height = (RENDER_HEIGHT * params.scale) as u16;
+        out.write_all(b"GIF89a")?;
+        out.write_all(&width.to_le_bytes())?;
+        out.write_all(&height.to_le_bytes())?;
+        // Global color table of 2^6 colors, 8 bits of color resolution
+        out.write_all(&[0b1111_0101, 0, 0])?;
+        for color in palette[..GLOBAL_COLORS].iter() {
+            out.write_all(&[color.0, color.1, color.2])?;
+        }
+        // Loop forever
+        out.write_all(&[0x21, 0xFF, 11])?;
+        out.write_all(b"NETSCAPE2.0")?;
+        out.write_all(&[3, 1, 0, 0, 0])?;
+        Ok(GifEncoder {
+            out,
+            params,
+            palette: *palette,
+            frame_duration: 100.0 * (params.frame_skip + 1) as f64 / region.frames_per_second(),
+            frames_seen: 0,
+            num_frames: 0,
+            elapsed: 0.0,
+            elapsed_written: 0,
+            previous: None,
+        })
+    }
+    // Call once per emulated frame; frames are dropped according to the frame skip.
+    pub fn add_frame(&mut self, ppu: &Ppu) -> Result<(), io::Error> {
+        let is_captured = self.frames_seen.is_multiple_of(self.params.frame_skip + 1);
+        self.frames_seen += 1;
+        if !is_captured {
+            return Ok(());
+        }
+        let pixels: Vec<u16> = (0..UNRENDER_SIZE)
+            .map(|i| (ppu.display_emphasis[i] as u16) << 6 | (ppu.display[i] & 0x3F) as u16)
+            .collect();
+        // Delays are rounded so the clip's total length doesn't drift
+        self.elapsed += self.frame_duration;
+        let delay = (self.elapsed.round() as u32 - self.elapsed_written).max(MIN_DELAY);
+        self.elapsed_written += delay;
+        let (x, y, width, height) = match &self.previous {
+            None => (0, 0, RENDER_WIDTH, RENDER_HEIGHT),
+            Some(previous) => changed_rect(previous, &pixels),
+        };
+        self.write_image(&pixels, (x, y, width, height), delay)?;
+        self.previous = Some(pixels);
+        self.num_frames += 1;
+        Ok(())
+    }
+    pub fn num_frames(&self) -> u32 {
+        self.num_frames
+    }
+    pub fn finish(mut self) -> Result<W, io::Error> {
+        self.out.write_all(&[0x3B])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+    fn write_image(
+        &mut self,
+        pixels: &[u16],
+        (x, y, width, height): (usize, usize, usize, usize),
+        delay: u32,
+    ) -> Result<(), io::Error> {
+        let scale = self.params.scale;
+        // Graphic control extension: leave the previous frame in place, no transparency
+        self.out.write_all(&[0x21, 0xF9, 4, 0b0000_0100])?;
+        self.out
+            .write_all(&(delay.min(0xFFFF) as u16).to_le_bytes())?;
+        self.out.write_all(&[0, 0])?;
+        // Image descriptor
+        self.out.write_all(&[0x2C])?;
+        for n in [x, y, width, height].iter() {
+            self.out.write_all(&((n * scale) as u16).to_le_bytes())?;
+        }
+        let mut indices = Vec::with_capacity(width * height * scale * scale);
+        let mut local_colors: Vec<u16> = (0..GLOBAL_COLORS as u16).collect();
+        let is_emphasized =
+            (y..y + height).any(|py| (x..x + width).any(|px| pixels[py * RENDER_WIDTH + px] >= 64));
+        for py in y..y + height {
+            let mut row = Vec::with_capacity(width * scale);
+            for px in x..x + width {
+                let pixel = pixels[py * RENDER_WIDTH + px];
+                let index = if !is_emphasized {
+                    pixel as u8
+                } else {
+                    local_color_index(&mut local_colors, pixel)
+                };
+                row.resize(row.len() + scale, index);
+            }
+            for _ in 0..scale {
+                indices.extend_from_slice(&row);
+            }
+        }
+        let min_code_size = if is_emphasized {
+            // Local color table of 2^8 colors
+            self.out.write_all(&[0b1000_0111])?;
+            for i in 0..256 {
+                let pixel = local_colors.get(i).cloned().unwrap_or(0);
+                let color = self.palette[pixel as usize];
+                self.out.write_all(&[color.0, color.1, color.2])?;
+            }
+            8
+        } else {
+            self.out.write_all(&[0])?;
+            6
+        };
+        self.out.write_all(&[min_code_size])?;
+        let compressed = lzw_compress(&indices, min_code_size);
+        for block in compressed.chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0])
+    }
+}
+
+// Local color tables start with the 64 unemphasized colors. More than 192 emphasized colors
+// would take several emphasis changes in one frame; those drop their emphasis bits.
+fn local_color_index(local_colors: &mut Vec<u16>, pixel: u16) -> u8 {
+    match local_colors.iter().position(|c| *c == pixel) {
+        Some(i) => i as u8,
+        None if local_colors.len() < 256 => {
+            local_colors.push(pixel);
+            (local_colors.len() - 1) as u8
+        }
+        None => (pixel & 0x3F) as u8,
+    }
+}
+
+// The smallest rectangle containing every changed pixel. GIF frames need at least one pixel.
+fn changed_rect(previous: &[u16], pixels: &[u16]) -> (usize, usize, usize, usize) {
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (RENDER_WIDTH, RENDER_HEIGHT, 0, 0);
+    for y in 0..RENDER_HEIGHT {
+        for x in 0..RENDER_WIDTH {
+            let i = y * RENDER_WIDTH + x;
+            if previous[i] != pixels[i] {
+                min_x = min_x.min(x);
+                min_y = min_y.min(y);
+                max_x = max_x.max(x);
+                max_y = max_y.max(y);
+            }
+        }
+    }
+    if min_x > max_x {
+        (0, 0, 1, 1)
+    } else {
+        (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1)
+    }
+}
+
+// Variable-length LZW codes, packed least significant bit first
+fn lzw_compress(indices: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear_code = 1u16 << min_code_size;
+    let end_code = clear_code + 1;
+    let mut out = Vec::new();
+    let (mut bit_buffer, mut num_bits) = (0u32, 0u8);
+    let mut write_code = |out: &mut Vec<u8>, code: u16, code_size: u8| {
+        bit_buffer |= (code as u32) << num_bits;
+        num_bits += code_size;
+        while num_bits >= 8 {
+            out.push(bit_buffer as u8);
+            bit_buffer >>= 8;
+            num_bits -= 8;
+        }
+    };
+    let mut dictionary: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next_code = end_code + 1;
+    let mut code_size = min_code_size + 1;
+    write_code(&mut out, clear_code, code_size);
+    let mut prefix: Option<u16> = None;
+    for index in indices {
+        let current = match prefix {
+            None => {
+                prefix = Some(*index as u16);
+                continue;
+            }
+            Some(current) => current,
+        };
+        if let Some(code) = dictionary.get(&(current, *index)) {
+            prefix = Some(*code);
+            continue;
+        }
+        write_code(&mut out, current, code_size);
+        if next_code == 1 << MAX_CODE_SIZE {
+            write_code(&mut out, clear_code, code_size);
+            dictionary.clear();
+            next_code = end_code + 1;
+            code_size = min_code_size + 1;
+        } else {
+            dictionary.insert((current, *index), next_code);
+            // The decoder widens its codes as soon as the table reaches the next power of two.
+            if next_code == 1 << code_size {
+                code_size += 1;
+            }
+            next_code += 1;
+        }
+        prefix = Some(*index as u16);
+    }
+    if let Some(current) = prefix {
+        write_code(&mut out, current, code_size);
+    }
+    write_code(&mut out, end_code, code_size);
+    if num_bits > 0 {
+        out.push(bit_buffer as u8);
+    }
+    out
+}
+
+// Runs the emulator for `num_frames` captured frames and writes them as a GIF
+pub fn capture_gif<W: Write>(
+    nes: &mut Nes,
+    out: W,
+    num_frames: u32,
+    params: GifParams,
+) -> Result<W, io::Error> {
+    let palette = *nes.ppu.palette();
+    let mut encoder = GifEncoder::new(out, &palette, nes.region(), params)?;
+    while encoder.num_frames() < num_frames {
+        nes.run_frame();
+        encoder.add_frame(&nes.ppu)?;
+    }
+    encoder.finish()
+}
+
+mod tests {
+    use super::*;
+
+    // Decodes one image's codes back into color indices
+    fn lzw_decompress(bytes: &[u8], min_code_size: u8) -> Vec<u8> {
+        let clear_code = 1usize << min_code_size;
+        let end_code = clear_code + 1;
+        let mut bit = 0;
+        let mut read_code = |code_size: u8| -> usize {
+            let mut x = 0;
+            for i in 0..code_size as usize {
+                x |= ((bytes[bit / 8] >> (bit % 8)) as usize & 1) << i;
+                bit += 1;
+            }
+            x
+        };
+        let mut out = Vec::new();
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut code_size = min_code_size + 1;
+        let mut previous: Option<Vec<u8>> = None;
+        loop {
+            let code = read_code(code_size);
+            if code == clear_code {
+                table = (0..clear_code).map(|i| vec![i as u8]).collect();
+                table.push(vec![]);
+                table.push(vec![]);
+                code_size = min_code_size + 1;
+                previous = None;
+                continue;
+            }
+            if code == end_code {
+                return out;
+            }
+            let entry = if code < table.len() {
+                table[code].clone()
+            } else {
+                let mut entry = previous.clone().unwrap();
+                entry.push(entry[0]);
+                entry
+            };
+            out.extend_from_slice(&entry);
+            if let Some(mut previous) = previous {
+                previous.push(entry[0]);
+                table.push(previous);
+                if table.len() == 1 << code_size && code_size < MAX_CODE_SIZE {
+                    code_size += 1;
+                }
+            }
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn test_lzw_round_trip() {
+        let mut indices: Vec<u8> = (0..64).collect();
+        indices.extend(vec![5; 3000]);
+        indices.extend((0..20000).map(|x| ((x * 7919) % 61) as u8));
+        let compressed = lzw_compress(&indices, 6);
+        assert_eq!(lzw_decompress(&compressed, 6), indices);
+        let indices: Vec<u8> = (0..30000).map(|x| ((x * 31) % 256) as u8).collect();
+        assert_eq!(lzw_decompress(&lzw_compress(&indices, 8), 8), indices);
+    }
+    #[test]
+    fn test_changed_rect() {
+        let previous = vec![0; UNRENDER_SIZE];
+        let mut pixels = previous.clone();
+        assert_eq!(changed_rect(&previous, &pixels), (0, 0, 1, 1));
+        pixels[10 * RENDER_WIDTH + 20] = 1;
+        pixels[12 * RENDER_WIDTH + 5] = 1;
+        assert_eq!(changed_rect(&previous, &pixels), (5, 10, 16, 3));
+    }
+    #[test]
+    fn test_local_color_index() {
+        let mut local_colors: Vec<u16> = (0..GLOBAL_COLORS as u16).collect();
+        assert_eq!(local_color_index(&mut local_colors, 0x16), 0x16);
+        assert_eq!(local_color_index(&mut local_colors, 1 << 6 | 0x16), 64);
+        assert_eq!(local_color_index(&mut local_colors, 1 << 6 | 0x16), 64);
+        for pixel in 0x80..0x80 + 191 {
+            local_color_index(&mut local_colors, pixel);
+        }
+        assert_eq!(local_colors.len(), 256);
+        assert_eq!(local_color_index(&mut local_colors, 7 << 6 | 0x30), 0x30);
+    }
+    #[test]
+    fn test_gif_encoder() {
+        let mut ppu = Ppu::new();
+        let params = GifParams {
+            frame_skip: 1,
+            scale: 2,
+        };
+        let palette = *ppu.palette();
+        let mut encoder = GifEncoder::new(Vec::new(), &palette, Region::Ntsc, params).unwrap();
+        for frame in 0..6 {
+            ppu.display[frame] = 0x16;
+            encoder.add_frame(&ppu).unwrap();
+        }
+        assert_eq!(encoder.num_frames(), 3);
+        let bytes = encoder.finish().unwrap();
+        assert_eq!(&bytes[..6], b"GIF89a");
+        assert_eq!(bytes[6..10], [0, 2, 224, 1]);
+        assert_eq!(bytes[13..16], [palette[0].0, palette[0].1, palette[0].2]);
+        assert_eq!(bytes[bytes.len() - 1], 0x3B);
+        // The first frame is 2 frames at 60.1 FPS, or 3/100ths of a second
+        let first_image = 13 + GLOBAL_COLORS * 3 + 19;
+        assert_eq!(bytes[first_image..first_image + 4], [0x21, 0xF9, 4, 4]);
+        assert_eq!(bytes[first_image + 4], 3);
+    }
+}
@@ -1,3 +1,6 @@
+use crate::input::PortDevice;
+use crate::ppu::{LayerMask, PixelFormat, UNRENDER_SIZE};
+use crate::scaler::Scaler;
 use crate::serialization::Savable;
 use log::{debug, error, trace};
 use std::io::{BufWriter, Read, Write};
@@ -16,6 +19,7 @@ pub enum Command {
     LoadRom(bool, String),
     StepFrame,
     RenderFrame(RenderStyle),
+    // Controller ids 0-3. Controllers 3 and 4 are connected through a Four Score.
     SetInputs(u8, u8),
     SaveState(String),
     LoadState(String),
@@ -25,6 +29,24 @@ pub enum Command {
     Peek(u16),
     Poke(u16, u8),
     SetRendering(bool),
+    // Writes a PNG of the current frame, scaled if the scaler isn't None
+    Screenshot(String, Scaler),
+    // Records each following frame to <basename>.y4m and <basename>.wav
+    StartAvRecording(String),
+    StopAvRecording,
+    // Hides layers from rendered frames, without affecting the emulation
+    SetLayers(LayerMask),
+    // Draws every sprite on a scanline instead of the first 8
+    SetUnlimitedSprites(bool),
+    // Aims a Zapper in port 2 at screen pixel (x, y) and sets its trigger. The first use plugs the
+    // Zapper in, replacing controller 2. Coordinates off the screen point it away.
+    SetZapper(u16, u16, bool),
+    // Plugs a device into port 0 or 1
+    SetPortDevice(u8, PortDevice),
+    // Sets the knob position and button of the Arkanoid controller in a port
+    SetArkanoid(u8, u8, bool),
+    // Sets the buttons held on the Power Pad in a port. Bit n-1 is button n.
+    SetPowerPad(u8, u16),
 }
 
 impl Default for Command {
@@ -40,6 +62,22 @@ use Command::*;
 pub enum RenderStyle {
     Plain = 0,
     Rgb = 1,
+    Rgba = 2,
+    Bgra = 3,
+}
+
+impl RenderStyle {
+    pub fn pixel_format(&self) -> PixelFormat {
+        match self {
+            RenderStyle::Plain => PixelFormat::PaletteIndex,
+            RenderStyle::Rgb => PixelFormat::Rgb24,
+            RenderStyle::Rgba => PixelFormat::Rgba8888,
+            RenderStyle::Bgra => PixelFormat::Bgra8888,
+        }
+    }
+    pub fn frame_size(&self) -> usize {
+        UNRENDER_SIZE * self.pixel_format().bytes_per_pixel()
+    }
 }
 
 impl Savable for Option<Command> {
@@ -94,6 +132,48 @@ impl Savable for Option<Command> {
                 write_byte(fh, 12);
                 write_value(fh, is_rendering);
             }
+            Screenshot(filename, scaler) => {
+                write_byte(fh, 13);
+                write_value::<String>(fh, filename);
+                write_value(fh, scaler);
+            }
+            StartAvRecording(basename) => {
+                write_byte(fh, 14);
+                write_value::<String>(fh, basename);
+            }
+            StopAvRecording => {
+                write_byte(fh, 15);
+            }
+            SetLayers(layers) => {
+                write_byte(fh, 16);
+                write_value(fh, layers);
+            }
+            SetUnlimitedSprites(unlimited_sprites) => {
+                write_byte(fh, 17);
+                write_value(fh, unlimited_sprites);
+            }
+            SetZapper(x, y, is_trigger_pulled) => {
+                write_byte(fh, 18);
+                write_value(fh, x);
+                write_value(fh, y);
+                write_value(fh, is_trigger_pulled);
+            }
+            SetPortDevice(port, device) => {
+                write_byte(fh, 19);
+                write_value(fh, port);
+                write_value(fh, device);
+            }
+            SetArkanoid(port, position, is_button_pressed) => {
+                write_byte(fh, 20);
+                write_value(fh, port);
+                write_value(fh, position);
+                write_value(fh, is_button_pressed);
+            }
+            SetPowerPad(port, buttons) => {
+                write_byte(fh, 21);
+                write_value(fh, port);
+                write_value(fh, buttons);
+            }
         };
         fh.flush().expect("Unable to flush buffer");
     }
@@ -120,6 +200,23 @@ impl Savable for Option<Command> {
             10 => Peek(read_value::<u16>(fh)),
             11 => Poke(read_value::<u16>(fh), read_value::<u8>(fh)),
             12 => SetRendering(read_value::<bool>(fh)),
+            13 => Screenshot(read_value::<String>(fh), read_value::<Scaler>(fh)),
+            14 => StartAvRecording(read_value::<String>(fh)),
+            15 => StopAvRecording,
+            16 => SetLayers(read_value::<LayerMask>(fh)),
+            17 => SetUnlimitedSprites(read_value::<bool>(fh)),
+            18 => SetZapper(
+                read_value::<u16>(fh),
+                read_value::<u16>(fh),
+                read_value::<bool>(fh),
+            ),
+            19 => SetPortDevice(read_value::<u8>(fh), read_value::<PortDevice>(fh)),
+            20 => SetArkanoid(
+                read_value::<u8>(fh),
+                read_value::<u8>(fh),
+                read_value::<bool>(fh),
+            ),
+            21 => SetPowerPad(read_value::<u8>(fh), read_value::<u16>(fh)),
             x => {
                 error!("Received command {}. Probably a sync error", x);
                 *self = None;
@@ -176,11 +273,7 @@ impl SocketHeadlessClient {
     }
     pub fn render_frame(&mut self, render_style: RenderStyle) -> Vec<u8> {
         RenderFrame(render_style).save(&mut self.0);
-        let bytes = match render_style as u8 {
-            0 => read_bytes(&mut self.0, crate::ppu::UNRENDER_SIZE),
-            1 => read_bytes(&mut self.0, crate::ppu::RENDER_SIZE),
-            x => panic!("Unknown render style {:?}", x),
-        };
+        let bytes = read_bytes(&mut self.0, render_style.frame_size());
         self.sync();
         bytes
     }
@@ -223,6 +316,42 @@ impl SocketHeadlessClient {
         SetRendering(is_rendering).save(&mut self.0);
         self.sync();
     }
+    pub fn screenshot(&mut self, filename: String, scaler: Scaler) {
+        Screenshot(filename, scaler).save(&mut self.0);
+        self.sync();
+    }
+    pub fn start_av_recording(&mut self, basename: String) {
+        StartAvRecording(basename).save(&mut self.0);
+        self.sync();
+    }
+    pub fn stop_av_recording(&mut self) {
+        StopAvRecording.save(&mut self.0);
+        self.sync();
+    }
+    pub fn set_layers(&mut self, layers: LayerMask) {
+        SetLayers(layers).save(&mut self.0);
+        self.sync();
+    }
+    pub fn set_unlimited_sprites(&mut self, unlimited_sprites: bool) {
+        SetUnlimitedSprites(unlimited_sprites).save(&mut self.0);
+        self.sync();
+    }
+    pub fn set_zapper(&mut self, x: u16, y: u16, is_trigger_pulled: bool) {
+        SetZapper(x, y, is_trigger_pulled).save(&mut self.0);
+        self.sync();
+    }
+    pub fn set_port_device(&mut self, port: u8, device: PortDevice) {
+        SetPortDevice(port, device).save(&mut self.0);
+        self.sync();
+    }
+    pub fn set_arkanoid(&mut self, port: u8, position: u8, is_button_pressed: bool) {
+        SetArkanoid(port, position, is_button_pressed).save(&mut self.0);
+        self.sync();
+    }
+    pub fn set_power_pad(&mut self, port: u8, buttons: u16) {
+        SetPowerPad(port, buttons).save(&mut self.0);
+        self.sync();
+    }
     fn sync(&mut self) {
         let byte = read_value::<u8>(&mut self.0);
         trace!("sync={}", byte);
@@ -0,0 +1,265 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use core::ptr::null_mut;
+use std::io::Read;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::arkanoid::Arkanoid;
+use crate::four_score::FourPlayerAdapter;
+use crate::joystick::Joystick;
+use crate::mapper::AddressSpace;
+use crate::nes::Nes;
+use crate::power_pad::PowerPad;
+use crate::serialization::Savable;
+use crate::zapper::Zapper;
+
+// What's plugged into a controller port
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum PortDevice {
+    #[default]
+    Joystick,
+    Zapper,
+    VsZapper,
+    Arkanoid,
+    PowerPad,
+}
+
+impl FromStr for PortDevice {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "joystick" | "controller" => Ok(PortDevice::Joystick),
+            "zapper" => Ok(PortDevice::Zapper),
+            "vs-zapper" => Ok(PortDevice::VsZapper),
+            "arkanoid" | "vaus" => Ok(PortDevice::Arkanoid),
+            "power-pad" | "family-trainer" => Ok(PortDevice::PowerPad),
+            _ => Err(format!("Unknown port device {}", s)),
+        }
+    }
+}
+
+impl Savable for PortDevice {
+    fn save(&self, fh: &mut dyn Write) {
+        let x = match self {
+            PortDevice::Joystick => 0u8,
+            PortDevice::Zapper => 1,
+            PortDevice::VsZapper => 2,
+            PortDevice::Arkanoid => 3,
+            PortDevice::PowerPad => 4,
+        };
+        x.save(fh);
+    }
+    fn load(&mut self, fh: &mut dyn Read) {
+        let mut x = 0u8;
+        x.load(fh);
+        *self = match x {
+            0 => PortDevice::Joystick,
+            1 => PortDevice::Zapper,
+            2 => PortDevice::VsZapper,
+            3 => PortDevice::Arkanoid,
+            4 => PortDevice::PowerPad,
+            _ => panic!("Unknown port device {}", x),
+        };
+    }
+}
+
+// The devices in both controller ports. Joysticks in a port go through the four player adapter
+// when there is one.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct InputConfig {
+    pub ports: [PortDevice; 2],
+    pub four_players: Option<FourPlayerAdapter>,
+}
+
+// Savestates record the connected devices, since each has its own amount of state.
+impl Savable for InputConfig {
+    fn save(&self, fh: &mut dyn Write) {
+        self.ports[0].save(fh);
+        self.ports[1].save(fh);
+        self.four_players.save(fh);
+    }
+    fn load(&mut self, fh: &mut dyn Read) {
+        self.ports[0].load(fh);
+        self.ports[1].load(fh);
+        self.four_players.load(fh);
+    }
+}
+
+impl InputConfig {
+    // https://wiki.nesdev.com/w/index.php/NES_2.0#Default_Expansion_Device
+    // None for devices that aren't emulated.
+    pub fn from_expansion_device(device: u8) -> Option<InputConfig> {
+        let ports = |port1, port2| InputConfig {
+            ports: [port1, port2],
+            four_players: None,
+        };
+        let four_players = |adapter| InputConfig {
+            four_players: Some(adapter),
+            ..InputConfig::default()
+        };
+        match device {
+            0x01 => Some(InputConfig::default()),
+            0x02 => Some(four_players(FourPlayerAdapter::FourScore)),
+            0x03 => Some(four_players(FourPlayerAdapter::Famicom)),
+            0x07 => Some(ports(PortDevice::VsZapper, PortDevice::Joystick)),
+            0x08 => Some(ports(PortDevice::Joystick, PortDevice::Zapper)),
+            0x09 => Some(ports(PortDevice::Zapper, PortDevice::Zapper)),
+            0x0B | 0x0C => Some(ports(PortDevice::Joystick, PortDevice::PowerPad)),
+            0x0F => Some(ports(PortDevice::Joystick, PortDevice::Arkanoid)),
+            _ => None,
+        }
+    }
+}
+
+// The connected devices, for frontends to feed inputs into. Each is indexed by port or
+// controller number, and null when it isn't plugged in.
+pub struct Inputs {
+    pub config: InputConfig,
+    pub joysticks: [*mut Joystick; 4],
+    pub zappers: [*mut Zapper; 2],
+    pub arkanoids: [*mut Arkanoid; 2],
+    pub power_pads: [*mut PowerPad; 2],
+}
+
+impl Inputs {
+    // Plugs new devices into both ports, replacing the old ones
+    pub fn connect(nes: &mut Nes, config: InputConfig) -> Inputs {
+        let mut inputs = Inputs {
+            config,
+            joysticks: [null_mut(); 4],
+            zappers: [null_mut(); 2],
+            arkanoids: [null_mut(); 2],
+            power_pads: [null_mut(); 2],
+        };
+        for port in 0..2 {
+            let device: Box<dyn AddressSpace> = match config.ports[port] {
+                PortDevice::Joystick => {
+                    let mut first = Box::new(Joystick::new());
+                    inputs.joysticks[port] = &mut *first;
+                    match config.four_players {
+                        None => first,
+                        Some(adapter) => {
+                            let mut second = Box::new(Joystick::new());
+                            inputs.joysticks[port + 2] = &mut *second;
+                            adapter.connect(port, first, second)
+                        }
+                    }
+                }
+                PortDevice::Zapper | PortDevice::VsZapper => {
+                    let mut zapper = Box::new(match config.ports[port] {
+                        PortDevice::VsZapper => Zapper::new_vs(&nes.ppu),
+                        _ => Zapper::new(&nes.ppu),
+                    });
+                    inputs.zappers[port] = &mut *zapper;
+                    zapper
+                }
+                PortDevice::Arkanoid => {
+                    let mut arkanoid = Box::new(Arkanoid::new());
+                    inputs.arkanoids[port] = &mut *arkanoid;
+                    arkanoid
+                }
+                PortDevice::PowerPad => {
+                    let mut power_pad = Box::new(PowerPad::new());
+                    inputs.power_pads[port] = &mut *power_pad;
+                    power_pad
+                }
+            };
+            nes.set_controller_port(port, device);
+        }
+        nes.set_input_config(config);
+        inputs
+    }
+    // Controller ids 0-3
+    pub fn joystick(&mut self, controller_id: usize) -> Option<&mut Joystick> {
+        unsafe { self.joysticks.get(controller_id)?.as_mut() }
+    }
+    pub fn zapper(&mut self, port: usize) -> Option<&mut Zapper> {
+        unsafe { self.zappers.get(port)?.as_mut() }
+    }
+    pub fn arkanoid(&mut self, port: usize) -> Option<&mut Arkanoid> {
+        unsafe { self.arkanoids.get(port)?.as_mut() }
+    }
+    pub fn power_pad(&mut self, port: usize) -> Option<&mut PowerPad> {
+        unsafe { self.power_pads.get(port)?.as_mut() }
+    }
+    // The buttons held on each controller, to carry over when reconnecting
+    pub fn joystick_buttons(&self) -> [u8; 4] {
+        let mut buttons = [0; 4];
+        for (i, joystick) in self.joysticks.iter().enumerate() {
+            if let Some(joystick) = unsafe { joystick.as_ref() } {
+                buttons[i] = joystick.buttons();
+            }
+        }
+        buttons
+    }
+}
+
+// Autofire: while a turbo button is held, its joystick button is pressed for `rate` frames, then
+// released for `rate` frames. Frontends feed the result to Joystick::set_buttons and movies, so
+// playback doesn't depend on the rate.
+pub struct Turbo {
+    rate: u32,
+    frames_held: u32,
+}
+
+impl Turbo {
+    pub fn new(rate: u32) -> Turbo {
+        Turbo {
+            rate: rate.max(1),
+            frames_held: 0,
+        }
+    }
+    pub fn set_rate(&mut self, rate: u32) {
+        self.rate = rate.max(1);
+    }
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+    // `held` is the turbo buttons held this frame, in joystick bit order. Returns the ones pressed.
+    pub fn next_frame(&mut self, held: u8) -> u8 {
+        if held == 0 {
+            self.frames_held = 0;
+            return 0;
+        }
+        let is_pressed = (self.frames_held / self.rate).is_multiple_of(2);
+        self.frames_held = self.frames_held.wrapping_add(1);
+        if is_pressed {
+            held
+        } else {
+            0
+        }
+    }
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expansion_devices() {
+        assert_eq!(
+            InputConfig::from_expansion_device(0x08),
+            Some(InputConfig {
+                ports: [PortDevice::Joystick, PortDevice::Zapper],
+                four_players: None,
+            })
+        );
+        assert_eq!(
+            InputConfig::from_expansion_device(0x02)
+                .unwrap()
+                .four_players,
+            Some(FourPlayerAdapter::FourScore)
+        );
+        assert_eq!(InputConfig::from_expansion_device(0x00), None);
+    }
+    #[test]
+    fn test_turbo() {
+        let mut turbo = Turbo::new(2);
+        let buttons: Vec<u8> = (0..6).map(|_| turbo.next_frame(0b01)).collect();
+        assert_eq!(buttons, vec![1, 1, 0, 0, 1, 1]);
+        // Letting go restarts the cycle, so the next press registers right away
+        assert_eq!(turbo.next_frame(0), 0);
+        assert_eq!(turbo.next_frame(0b11), 0b11);
+    }
+}
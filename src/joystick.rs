@@ -39,9 +39,13 @@ impl Joystick {
     pub fn set_buttons(&mut self, button_mask: u8) {
         self.buttons = button_mask;
     }
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+    // Official controllers shift in 1s, so every read after the 8th returns 1.
     fn get_next_button(&self) -> u8 {
         let byte = self.buttons_register.get() & 1;
-        let new_buttons_register = self.buttons_register.get() >> 1;
+        let new_buttons_register = (self.buttons_register.get() >> 1) | 0x80;
         self.buttons_register.set(new_buttons_register);
         return byte;
     }
@@ -63,3 +67,85 @@ impl AddressSpace for Joystick {
         self.reset_from_strobe();
     }
 }
+
+// https://wiki.nesdev.com/w/index.php/Standard_controller
+// $4016 and $4017 are shared: writes to $4016 strobe both ports, reads from each address come
+// from its port, and writes to $4017 go to the APU frame counter instead.
+//
+// Reads only drive the data lines D0-D4, which is where each port's device puts its bits: D0
+// for controllers, D3 and D4 for the Zapper and the Power Pad. The other lines are open bus.
+pub struct ControllerPorts {
+    port1: Box<dyn AddressSpace>,
+    port2: Box<dyn AddressSpace>,
+    frame_counter: Box<dyn AddressSpace>,
+}
+
+impl Savable for ControllerPorts {
+    fn save(&self, fh: &mut dyn Write) {
+        self.port1.save(fh);
+        self.port2.save(fh);
+        self.frame_counter.save(fh);
+    }
+    fn load(&mut self, fh: &mut dyn Read) {
+        self.port1.load(fh);
+        self.port2.load(fh);
+        self.frame_counter.load(fh);
+    }
+}
+
+impl ControllerPorts {
+    pub fn new(
+        port1: Box<dyn AddressSpace>,
+        port2: Box<dyn AddressSpace>,
+        frame_counter: Box<dyn AddressSpace>,
+    ) -> ControllerPorts {
+        ControllerPorts {
+            port1,
+            port2,
+            frame_counter,
+        }
+    }
+    // Plugs a device into port 0 ($4016) or 1 ($4017), replacing the previous one
+    pub fn set_port(&mut self, port: usize, device: Box<dyn AddressSpace>) {
+        match port {
+            0 => self.port1 = device,
+            1 => self.port2 = device,
+            _ => panic!("Invalid controller port {}", port),
+        }
+    }
+}
+
+// The lines a device on a controller port can drive
+pub const CONTROLLER_DATA_LINES: u8 = 0x1F;
+pub const DATA_LINE_D0: u8 = 1 << 0;
+pub const DATA_LINE_D1: u8 = 1 << 1;
+pub const DATA_LINE_D3: u8 = 1 << 3;
+pub const DATA_LINE_D4: u8 = 1 << 4;
+
+// https://wiki.nesdev.com/w/index.php/Open_bus_behavior
+// The CPU doesn't track its data bus, so this assumes an absolute read like `LDA $4016`, which
+// leaves the high byte of the address on the bus.
+fn open_bus(ptr: u16) -> u8 {
+    (ptr >> 8) as u8 & !CONTROLLER_DATA_LINES
+}
+
+impl AddressSpace for ControllerPorts {
+    fn peek(&self, ptr: u16) -> u8 {
+        let data = match ptr {
+            0x4016 => self.port1.peek(ptr),
+            0x4017 => self.port2.peek(ptr),
+            _ => panic!("Invalid controller port read {:x}", ptr),
+        };
+        open_bus(ptr) | (data & CONTROLLER_DATA_LINES)
+    }
+    fn poke(&mut self, ptr: u16, v: u8) {
+        match ptr {
+            0x4016 => {
+                self.port1.poke(ptr, v);
+                self.port2.poke(ptr, v);
+            }
+            0x4017 => self.frame_counter.poke(ptr, v),
+            _ => panic!("Invalid controller port write {:x}", ptr),
+        }
+    }
+}
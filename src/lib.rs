@@ -1,11 +1,27 @@
 pub mod apu;
+pub mod arkanoid;
+pub mod av_recorder;
+pub mod bindings;
 pub mod c6502;
 pub mod common;
+pub mod four_score;
+pub mod gif;
 pub mod headless_protocol;
+pub mod input;
 pub mod joystick;
 pub mod mapper;
 pub mod nes;
+pub mod ntsc_filter;
+pub mod palette;
+pub mod png;
+pub mod power_pad;
 pub mod ppu;
+pub mod ppu_viewer;
+pub mod region;
+pub mod scaler;
 pub mod serialization;
+pub mod test_rom;
+pub mod video;
+pub mod zapper;
 
 extern crate sdl2;
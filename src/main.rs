@@ -2,20 +2,42 @@
 #![allow(unused_mut)]
 
 mod apu;
+mod arkanoid;
+mod av_recorder;
+mod bindings;
 mod c6502;
 mod common;
+mod four_score;
+mod gif;
+mod input;
 mod joystick;
 mod mapper;
 mod nes;
+mod ntsc_filter;
+mod palette;
+mod png;
+mod power_pad;
 mod ppu;
+mod ppu_viewer;
+mod region;
+mod scaler;
 mod serialization;
+mod test_rom;
+mod video;
+mod zapper;
 
 extern crate sdl2;
 
+use clap::{Parser, Subcommand};
 use sdl2::audio::{AudioCallback, AudioQueue, AudioSpecDesired};
 use sdl2::controller::GameController;
-use sdl2::event::Event;
+use sdl2::controller::{Axis, Button};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::KeyboardState;
 use sdl2::keyboard::Keycode;
+use sdl2::keyboard::Mod;
+use sdl2::keyboard::Scancode;
+use sdl2::mouse::MouseButton;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::render::Canvas;
@@ -29,6 +51,7 @@ use sdl2::EventPump;
 use sdl2::GameControllerSubsystem;
 use sdl2::VideoSubsystem;
 use std::fs::File;
+use std::io::BufWriter;
 use std::io::ErrorKind;
 use std::os::raw::c_int;
 use std::ptr::NonNull;
@@ -37,13 +60,26 @@ use std::time::{Duration, Instant};
 use core::ptr::null_mut;
 
 use crate::apu::Apu;
+use crate::av_recorder::AvRecorder;
+use crate::bindings::{read_bindings, Bindings, BoundInput, Hotkey, HOTKEYS};
+use crate::four_score::FourPlayerAdapter;
+use crate::gif::{GifEncoder, GifParams};
+use crate::input::{Inputs, PortDevice, Turbo};
 use crate::joystick::Joystick;
 use crate::mapper::AddressSpace;
 use crate::nes::Nes;
 use crate::nes::Tas;
-use crate::nes::{load_ines, read_ines};
+use crate::nes::{load_ines, read_ines, savestate_input_config};
+use crate::ntsc_filter::{NtscFilter, NtscFilterParams, NtscPreset};
+use crate::palette::{generate_ntsc_palette, read_palette, NtscPaletteParams};
+use crate::png::{screenshot, write_png};
 use crate::ppu::*;
+use crate::ppu_viewer::*;
+use crate::region::Region;
+use crate::scaler::Scaler;
 use crate::serialization::Savable;
+use crate::test_rom::{run_test_rom, TestRomStatus};
+use crate::video::VideoPipeline;
 
 extern "C" {
     fn emscripten_set_main_loop(m: extern "C" fn(), fps: c_int, infinite: c_int);
@@ -59,11 +95,16 @@ const RECORDING: bool = true;
 const ROM_BEGIN_SAVESTATE: &'static str = "initial.state";
 const DEFAULT_SAVESTATE: &'static str = "save.state";
 const DEFAULT_RECORDING: &'static str = "save.video";
+const DEFAULT_AV_RECORDING: &'static str = "recording";
+const DEFAULT_BINDINGS: &'static str = "bindings.toml";
+// Sticks and triggers count as pressed when pushed at least halfway
+const AXIS_THRESHOLD: i16 = 16384;
 
 struct GlobalState {
     sdl_context: *mut sdl2::Sdl,
-    joystick1: *mut Joystick,
-    joystick2: *mut Joystick,
+    inputs: Inputs,
+    bindings: SdlBindings,
+    turbos: Vec<Turbo>,
     video_subsystem: *mut VideoSubsystem,
     audio_subsystem: *mut AudioSubsystem,
     controller_subsystem: *mut GameControllerSubsystem,
@@ -74,22 +115,120 @@ struct GlobalState {
     texture: *mut Texture<'static>,
     sdl_controller1: *mut sdl2::controller::GameController,
     sdl_controller2: *mut sdl2::controller::GameController,
+    sdl_controller3: *mut sdl2::controller::GameController,
+    sdl_controller4: *mut sdl2::controller::GameController,
     tas: *mut Tas,
     tas_frame: usize,
     turbo_mode: bool,
+    is_paused: bool,
+    video: VideoPipeline,
+    frames_run: u32,
+    av_recorder: Option<AvRecorder>,
+    av_basename: String,
+    av_start_frame: Option<u32>,
+    av_num_frames: Option<u32>,
+    gif_encoder: Option<GifEncoder<BufWriter<File>>>,
+    gif_params: GifParams,
+    gif_frames: Option<u32>,
+    debug_windows: Option<DebugWindows>,
+}
+
+#[derive(Parser)]
+#[clap(name = "nes-emulator")]
+struct Opts {
+    /// Overrides the region(ntsc, pal or dendy) detected from the ROM header
+    #[clap(long = "region")]
+    region: Option<Region>,
+    /// Loads colors from a 192-byte or 1536-byte .pal file
+    #[clap(long = "palette", conflicts_with = "ntsc-palette")]
+    palette: Option<String>,
+    /// Generates colors by decoding the PPU's NTSC signal
+    #[clap(long = "ntsc-palette")]
+    ntsc_palette: bool,
+    /// Hue rotation of the --ntsc-palette colors, in degrees
+    #[clap(long = "hue", default_value = "0.0")]
+    hue: f64,
+    /// Saturation multiplier of the --ntsc-palette colors
+    #[clap(long = "saturation", default_value = "1.0")]
+    saturation: f64,
+    /// Contrast multiplier of the --ntsc-palette colors
+    #[clap(long = "contrast", default_value = "1.0")]
+    contrast: f64,
+    /// Brightness added to the --ntsc-palette colors
+    #[clap(long = "brightness", default_value = "0.0")]
+    brightness: f64,
+    /// Simulates the video signal reaching a TV(composite, svideo or rgb)
+    #[clap(long = "ntsc-filter")]
+    ntsc_filter: Option<NtscPreset>,
+    /// Upscales frames on the CPU(none, scale2x, scale3x, hq2x or xbr)
+    #[clap(long = "scaler", default_value = "none")]
+    scaler: Scaler,
+    /// Darkens every other scanline, from 0.0(off) to 1.0(black)
+    #[clap(long = "scanlines", default_value = "0.0")]
+    scanlines: f64,
+    /// Records video to <BASENAME>.y4m and audio to <BASENAME>.wav
+    #[clap(long = "record-av")]
+    record_av: Option<String>,
+    /// The frame --record-av starts recording on, counting from launch
+    #[clap(long = "record-av-start", default_value = "0")]
+    record_av_start: u32,
+    /// Stops --record-av after this many frames
+    #[clap(long = "record-av-frames")]
+    record_av_frames: Option<u32>,
+    /// Makes the GIF hotkey capture this many frames, instead of until it's pressed again
+    #[clap(long = "gif-frames")]
+    gif_frames: Option<u32>,
+    /// Frames dropped between each frame of a captured GIF
+    #[clap(long = "gif-skip", default_value = "1")]
+    gif_skip: u32,
+    /// Pixel scale of captured GIFs
+    #[clap(long = "gif-scale", default_value = "1")]
+    gif_scale: usize,
+    /// Connects controllers 3 and 4 through a four player adapter(four-score or famicom)
+    #[clap(long = "four-players")]
+    four_players: Option<FourPlayerAdapter>,
+    /// The device in port 1(joystick, zapper, vs-zapper, arkanoid or power-pad). Defaults to the
+    /// one in the ROM header, or a joystick.
+    #[clap(long = "port1")]
+    port1: Option<PortDevice>,
+    /// The device in port 2
+    #[clap(long = "port2")]
+    port2: Option<PortDevice>,
+    /// Loads key, controller and hotkey bindings from a file. Defaults to bindings.toml when it
+    /// exists.
+    #[clap(long = "bindings")]
+    bindings: Option<String>,
+    /// Draws every sprite on a scanline, instead of only the first 8
+    #[clap(long = "unlimited-sprites")]
+    unlimited_sprites: bool,
+    /// Opens windows showing the nametables, pattern tables, sprites and palettes
+    #[clap(long = "debug-windows")]
+    debug_windows: bool,
+    #[clap(subcommand)]
+    command: Option<SubCommand>,
+}
+
+#[derive(Subcommand)]
+enum SubCommand {
+    /// Runs a test ROM that reports its result at $6000, and exits with its status
+    TestRom {
+        rom: String,
+        #[clap(long = "max-frames", default_value = "3600")]
+        max_frames: u32,
+    },
 }
 
 static mut GLOBAL_STATE: Option<GlobalState> = None;
 static mut TEXTURE_CREATOR: Option<TextureCreator<WindowContext>> = None;
 fn main() {
+    let opts = Opts::parse();
+    if let Some(SubCommand::TestRom { rom, max_frames }) = opts.command {
+        run_test_rom_command(rom, max_frames);
+    }
     let mut sdl_context = Box::new(sdl2::init().unwrap());
     let mut video_subsystem = Box::new(sdl_context.video().unwrap());
     let mut controller_subsystem = Box::new(sdl_context.game_controller().unwrap());
     let mut audio_subsystem = Box::new(sdl_context.audio().unwrap());
-    let mut joystick1 = Box::new(Joystick::new());
-    let mut joystick2 = Box::new(Joystick::new());
-    let joystick1_ptr = (&mut *joystick1) as *mut Joystick;
-    let joystick2_ptr = (&mut *joystick2) as *mut Joystick;
     let window = video_subsystem
         .window(
             "NES emulator",
@@ -102,20 +241,89 @@ fn main() {
 
     let mut canvas = Box::new(window.into_canvas().build().unwrap());
     let texture_creator = canvas.texture_creator();
+    let picture = NtscPaletteParams {
+        hue: opts.hue,
+        saturation: opts.saturation,
+        contrast: opts.contrast,
+        brightness: opts.brightness,
+    };
+    let ntsc_filter = opts.ntsc_filter.map(|preset| {
+        NtscFilter::new(NtscFilterParams {
+            picture,
+            ..NtscFilterParams::new(preset)
+        })
+    });
+    let video = VideoPipeline::new(ntsc_filter, opts.scaler, opts.scanlines);
+    let (texture_width, texture_height) = video.output_size();
     let mut texture = {
         let mut tex = texture_creator
             .create_texture(
                 PixelFormatEnum::RGB24,
                 TextureAccess::Streaming,
-                RENDER_WIDTH as u32,
-                RENDER_HEIGHT as u32,
+                texture_width as u32,
+                texture_height as u32,
             )
             .unwrap();
         unsafe { Box::new(std::mem::transmute(tex)) }
     };
-    let mut nes = Box::new(create_nes(joystick1, joystick2));
-    match File::open(ROM_BEGIN_SAVESTATE) {
-        Ok(mut fh) => nes.load(&mut fh),
+    let mut nes = Box::new(create_nes());
+    if let Some(region) = opts.region {
+        nes.override_region(region);
+    }
+    if let Some(filename) = opts.palette.clone() {
+        match read_palette(filename.clone()) {
+            Ok(palette) => nes.ppu.set_palette(palette),
+            Err(e) => panic!("Unable to load palette {} {:?}", filename, e),
+        }
+    } else if opts.ntsc_palette {
+        nes.ppu.set_palette(generate_ntsc_palette(&picture));
+    }
+    nes.ppu.set_unlimited_sprites(opts.unlimited_sprites);
+    let mut input_config = nes.default_input().unwrap_or_default();
+    if let Some(device) = opts.port1 {
+        input_config.ports[0] = device;
+    }
+    if let Some(device) = opts.port2 {
+        input_config.ports[1] = device;
+    }
+    if opts.four_players.is_some() {
+        input_config.four_players = opts.four_players;
+    }
+    let inputs = Inputs::connect(&mut nes, input_config);
+    let bindings_file = opts
+        .bindings
+        .clone()
+        .unwrap_or_else(|| DEFAULT_BINDINGS.to_string());
+    let is_known = |input: &BoundInput| SdlInput::new(input).is_some();
+    let bindings = match read_bindings(bindings_file.clone(), &is_known) {
+        Ok(bindings) => bindings,
+        Err(ref e) if e.kind() == ErrorKind::NotFound && opts.bindings.is_none() => {
+            Bindings::default()
+        }
+        Err(e) => panic!("Unable to load bindings {} {:?}", bindings_file, e),
+    };
+    let turbos = bindings
+        .turbo_rates
+        .iter()
+        .map(|rate| Turbo::new(*rate))
+        .collect();
+    let bindings = SdlBindings::new(&bindings);
+    match std::fs::read(ROM_BEGIN_SAVESTATE) {
+        // The devices picked from the command line and ROM header win over the savestate's
+        Ok(savestate) => match savestate_input_config(&savestate) {
+            Ok(config) if config != input_config => eprintln!(
+                "Not loading {}, which was saved with other input devices",
+                ROM_BEGIN_SAVESTATE
+            ),
+            result => {
+                if let Err(e) = result.and_then(|_| nes.load_state(&mut savestate.as_slice())) {
+                    eprintln!(
+                        "Not loading incompatible savestate {}: {}",
+                        ROM_BEGIN_SAVESTATE, e
+                    );
+                }
+            }
+        },
         Err(ref e) if e.kind() == ErrorKind::NotFound => {
             if let Ok(mut fh) = File::create(ROM_BEGIN_SAVESTATE) {
                 nes.save(&mut fh);
@@ -147,11 +355,17 @@ fn main() {
     let mut audio_device = Box::new(audio_subsystem.open_queue(None, &desired_spec).unwrap());
     audio_device.resume();
     let mut event_pump = Box::new(sdl_context.event_pump().unwrap());
+    let debug_windows = if opts.debug_windows {
+        Some(DebugWindows::new(&video_subsystem))
+    } else {
+        None
+    };
     unsafe {
         GLOBAL_STATE = Some(GlobalState {
             sdl_context: &mut *sdl_context,
-            joystick1: joystick1_ptr,
-            joystick2: joystick2_ptr,
+            inputs,
+            bindings,
+            turbos,
             video_subsystem: &mut *video_subsystem,
             audio_subsystem: &mut *audio_subsystem,
             controller_subsystem: &mut *controller_subsystem,
@@ -162,15 +376,39 @@ fn main() {
             texture: &mut *texture,
             sdl_controller1: null_mut(),
             sdl_controller2: null_mut(),
+            sdl_controller3: null_mut(),
+            sdl_controller4: null_mut(),
             tas: &mut *tas,
             tas_frame: 0,
             turbo_mode: false,
+            is_paused: false,
+            video,
+            frames_run: 0,
+            av_recorder: None,
+            av_basename: opts
+                .record_av
+                .clone()
+                .unwrap_or_else(|| DEFAULT_AV_RECORDING.to_string()),
+            av_start_frame: opts.record_av.as_ref().map(|_| opts.record_av_start),
+            av_num_frames: opts.record_av_frames,
+            gif_encoder: None,
+            gif_params: GifParams {
+                frame_skip: opts.gif_skip,
+                scale: opts.gif_scale.max(1),
+            },
+            gif_frames: opts.gif_frames,
+            debug_windows,
         });
     }
 
     if cfg!(target_os = "emscripten") {
         // void emscripten_set_main_loop(em_callback_func func, int fps, int simulate_infinite_loop);
-        unsafe { emscripten_set_main_loop(main_loop, 60, 1) };
+        let fps = unsafe {
+            (*GLOBAL_STATE.as_ref().unwrap().nes)
+                .region()
+                .frames_per_second()
+        };
+        unsafe { emscripten_set_main_loop(main_loop, fps.round() as c_int, 1) };
         loop {}
     } else {
         let mut every_second = Instant::now();
@@ -196,12 +434,30 @@ fn main() {
     //std::unreachable!();
 }
 
+fn run_test_rom_command(filename: String, max_frames: u32) -> ! {
+    let result = match run_test_rom(filename.clone(), max_frames) {
+        Ok(result) => result,
+        Err(e) => panic!("Unable to load ROM {} {:?}", filename, e),
+    };
+    match result.status {
+        TestRomStatus::Passed => println!("PASSED {}", filename),
+        TestRomStatus::Failed(code) => println!("FAILED({}) {}", code, filename),
+        TestRomStatus::TimedOut => println!("TIMED OUT {}", filename),
+    }
+    if !result.message.is_empty() {
+        println!("{}", result.message.trim_end());
+    }
+    std::process::exit(match result.status {
+        TestRomStatus::Passed => 0,
+        TestRomStatus::Failed(_) => 1,
+        TestRomStatus::TimedOut => 2,
+    });
+}
+
 extern "C" fn main_loop() {
     let now = Instant::now();
     let st = unsafe { GLOBAL_STATE.as_mut().unwrap() };
     // let mut sdl_context = unsafe { &mut *st.sdl_context };
-    let joystick1: &mut Joystick = unsafe { &mut *st.joystick1 };
-    let joystick2: &mut Joystick = unsafe { &mut *st.joystick2 };
     let mut nes = unsafe { &mut *st.nes };
     let mut event_pump = unsafe { &mut *st.event_pump };
     let mut audio_device = unsafe { &mut *st.audio_device };
@@ -222,6 +478,10 @@ extern "C" fn main_loop() {
     //           );
 
     for event in event_pump.poll_iter() {
+        if let Some(hotkey) = st.bindings.event_hotkey(&event) {
+            run_hotkey(st, nes, tas, hotkey);
+            continue;
+        }
         match event {
             // Exit game
             Event::Quit { .. }
@@ -229,57 +489,86 @@ extern "C" fn main_loop() {
                 keycode: Some(Keycode::Escape),
                 ..
             } => {
+                stop_av_recording(st);
+                stop_gif_capture(st);
                 std::process::exit(0);
             }
-            // Break CPU debugger
-            Event::KeyDown {
-                keycode: Some(Keycode::Pause),
+            // With debug windows open, closing the main window doesn't quit by itself.
+            Event::Window {
+                win_event: WindowEvent::Close,
+                window_id,
                 ..
             } => {
-                nes.break_debugger();
+                if window_id == canvas.window().id() {
+                    stop_av_recording(st);
+                    stop_gif_capture(st);
+                    std::process::exit(0);
+                } else if let Some(debug_windows) = st.debug_windows.as_mut() {
+                    debug_windows.close(window_id);
+                }
             }
-            // Save state
-            Event::KeyDown {
-                keycode: Some(Keycode::F5),
-                ..
-            } => {
-                let mut file = File::create(DEFAULT_SAVESTATE).unwrap();
-                nes.save(&mut file);
-                let mut tas_file = File::create(DEFAULT_RECORDING).unwrap();
-                tas.save(&mut tas_file);
+            // Clicking a sprite in the sprite window hides or shows it
+            Event::MouseButtonDown {
+                window_id, x, y, ..
+            } if st
+                .debug_windows
+                .as_ref()
+                .map_or(false, |w| w.sprites.id() == window_id) =>
+            {
+                let scale = st.debug_windows.as_ref().unwrap().sprites.scale as i32;
+                let (column, row) = (x / scale / 8, y / scale / 16);
+                if (0..8).contains(&column) && (0..8).contains(&row) {
+                    let mut layers = nes.ppu.layers();
+                    layers.toggle_sprite((row * 8 + column) as u8);
+                    nes.ppu.set_layers(layers);
+                }
             }
-            // Load State
-            Event::KeyDown {
-                keycode: Some(Keycode::F6),
-                ..
-            } => {
-                let mut file = File::open(DEFAULT_SAVESTATE).unwrap();
-                nes.load(&mut file);
-                let mut tas_file = File::open(DEFAULT_RECORDING).unwrap();
-                tas.load(&mut tas_file);
+            // Clicking the pattern tables cycles through the palettes they're drawn with
+            Event::MouseButtonDown { window_id, .. }
+                if st
+                    .debug_windows
+                    .as_ref()
+                    .map_or(false, |w| w.pattern_tables.id() == window_id) =>
+            {
+                let debug_windows = st.debug_windows.as_mut().unwrap();
+                debug_windows.pattern_palette = (debug_windows.pattern_palette + 1) % 8;
             }
-            // Play recording from initial state
-            Event::KeyDown {
-                keycode: Some(Keycode::F7),
-                ..
-            } => {
-                let mut tas_fh = File::open(DEFAULT_RECORDING).unwrap();
-                tas.load(&mut tas_fh);
-                let mut ss_fh = File::open(ROM_BEGIN_SAVESTATE).unwrap();
-                nes.load(&mut ss_fh);
-                st.tas_frame = 0;
-                nes.cpu.poke(0x075a, 3);
+            // Aim Zappers and turn Arkanoid knobs with the mouse, and fire with the left button
+            Event::MouseMotion {
+                window_id, x, y, ..
+            } if window_id == canvas.window().id() => {
+                let pointer = window_to_screen(canvas, x, y);
+                let fraction = x as f64 / canvas.window().size().0.max(1) as f64;
+                for port in 0..2 {
+                    if let Some(zapper) = st.inputs.zapper(port) {
+                        zapper.set_pointer(pointer);
+                    }
+                    if let Some(arkanoid) = st.inputs.arkanoid(port) {
+                        arkanoid.set_position_fraction(fraction);
+                    }
+                }
             }
-            // Begin recording at current point
-            Event::KeyDown {
-                keycode: Some(Keycode::F8),
+            Event::Window {
+                win_event: WindowEvent::Leave,
+                window_id,
                 ..
-            } => {
-                let mut ss_fh = File::create(ROM_BEGIN_SAVESTATE).unwrap();
-                nes.save(&mut ss_fh);
-                *tas = Tas::new();
-                st.tas_frame = 0;
+            } if window_id == canvas.window().id() => {
+                for port in 0..2 {
+                    if let Some(zapper) = st.inputs.zapper(port) {
+                        zapper.set_pointer(None);
+                    }
+                }
             }
+            Event::MouseButtonDown {
+                mouse_btn: MouseButton::Left,
+                window_id,
+                ..
+            } if window_id == canvas.window().id() => set_mouse_button(&mut st.inputs, true),
+            Event::MouseButtonUp {
+                mouse_btn: MouseButton::Left,
+                window_id,
+                ..
+            } if window_id == canvas.window().id() => set_mouse_button(&mut st.inputs, false),
             // Attach controller
             Event::ControllerDeviceAdded { which: id, .. } => {
                 eprintln!("DEBUG - CONTROLLER ADDED - {}", id);
@@ -292,40 +581,95 @@ extern "C" fn main_loop() {
                         st.sdl_controller2 =
                             Box::leak(Box::new(controller_subsystem.open(id).unwrap()))
                     }
+                    2 => {
+                        st.sdl_controller3 =
+                            Box::leak(Box::new(controller_subsystem.open(id).unwrap()))
+                    }
+                    3 => {
+                        st.sdl_controller4 =
+                            Box::leak(Box::new(controller_subsystem.open(id).unwrap()))
+                    }
                     _ => eprintln!("DEBUG - UNEXPECTED CONTROLLER ID {}", id),
                 }
             }
-            // Toggle Turbo Mode
-            Event::KeyDown {
-                keycode: Some(Keycode::Tab),
-                ..
-            } => {
-                st.turbo_mode = !st.turbo_mode;
-            }
             _ => {}
         }
     }
 
+    if st.is_paused {
+        canvas.present();
+        ::std::thread::sleep(Duration::from_secs_f64(
+            1.0 / nes.region().frames_per_second(),
+        ));
+        return;
+    }
+    let keyboard = event_pump.keyboard_state();
+    let sdl_controllers = [
+        st.sdl_controller1,
+        st.sdl_controller2,
+        st.sdl_controller3,
+        st.sdl_controller4,
+    ];
+    let mut button_masks = [0; 4];
+    for (controller_id, button_mask) in button_masks.iter_mut().enumerate() {
+        let controller = sdl_controllers[controller_id];
+        let buttons = &st.bindings.controllers[controller_id];
+        let turbo_buttons = &st.bindings.turbo[controller_id];
+        let turbo_held = get_button_mask(turbo_buttons, &keyboard, controller);
+        *button_mask = get_button_mask(buttons, &keyboard, controller)
+            | st.turbos[controller_id].next_frame(turbo_held);
+    }
     let frame = st.tas_frame;
-    let j1_bmask = tas.get_inputs(frame).unwrap_or_else(|| {
-        let buttons = get_button_mask(st.sdl_controller1);
+    button_masks[0] = tas.get_inputs(frame).unwrap_or_else(|| {
+        let buttons = button_masks[0];
         if RECORDING {
             tas.record_frame(frame, buttons);
         }
         buttons
     });
     st.tas_frame += 1;
-    let j2_bmask = get_button_mask(st.sdl_controller2);
-    joystick1.set_buttons(j1_bmask);
-    joystick2.set_buttons(j2_bmask);
+    for (controller_id, button_mask) in button_masks.iter().enumerate() {
+        if let Some(joystick) = st.inputs.joystick(controller_id) {
+            joystick.set_buttons(*button_mask);
+        }
+    }
+    let power_pad_buttons = get_power_pad_buttons(&st.bindings.power_pad, &keyboard);
+    for port in 0..2 {
+        if let Some(power_pad) = st.inputs.power_pad(port) {
+            power_pad.set_buttons(power_pad_buttons);
+        }
+    }
+    if st.av_start_frame == Some(st.frames_run) {
+        start_av_recording(st, nes);
+    }
     nes.run_frame();
-    present_frame(&mut canvas, &mut texture, &nes.ppu.render());
+    st.frames_run += 1;
+    if let Some(recorder) = st.av_recorder.as_mut() {
+        if let Err(e) = recorder.record_frame(&nes.ppu, &nes.apu.samples) {
+            eprintln!("Unable to record frame {:?}", e);
+        }
+        if Some(recorder.num_frames()) == st.av_num_frames.map(|n| n as u64) {
+            stop_av_recording(st);
+        }
+    }
+    if let Some(encoder) = st.gif_encoder.as_mut() {
+        if let Err(e) = encoder.add_frame(&nes.ppu) {
+            eprintln!("Unable to capture GIF frame {:?}", e);
+        }
+        if Some(encoder.num_frames()) == st.gif_frames {
+            stop_gif_capture(st);
+        }
+    }
+    present_frame(&mut canvas, &mut texture, &nes.ppu, &mut st.video);
     enqueue_frame_audio(&audio_device, &mut nes.apu.samples);
+    if let Some(debug_windows) = st.debug_windows.as_mut() {
+        debug_windows.present(&nes.ppu);
+    }
 
     canvas.present();
 
     let after = Instant::now();
-    let target_millis = Duration::from_millis(1000 / 60);
+    let target_millis = Duration::from_secs_f64(1.0 / nes.region().frames_per_second());
     let sleep_millis = target_millis.checked_sub(after - now);
     match sleep_millis {
         None => {} // Took too long last frame
@@ -394,7 +738,177 @@ impl AudioCallback for ApuSampler {
     }
 }
 
-fn create_nes(joystick1: Box<dyn AddressSpace>, joystick2: Box<dyn AddressSpace>) -> Nes {
+struct DebugWindow {
+    canvas: Canvas<Window>,
+    texture: Texture<'static>,
+    scale: usize,
+    is_open: bool,
+}
+
+impl DebugWindow {
+    fn new(
+        video_subsystem: &VideoSubsystem,
+        title: &str,
+        width: usize,
+        height: usize,
+        scale: usize,
+    ) -> DebugWindow {
+        let window = video_subsystem
+            .window(title, (width * scale) as u32, (height * scale) as u32)
+            .build()
+            .unwrap();
+        let canvas = window.into_canvas().build().unwrap();
+        // Debug windows live until the emulator exits
+        let texture_creator = Box::leak(Box::new(canvas.texture_creator()));
+        let texture = texture_creator
+            .create_texture(
+                PixelFormatEnum::RGB24,
+                TextureAccess::Streaming,
+                width as u32,
+                height as u32,
+            )
+            .unwrap();
+        DebugWindow {
+            canvas,
+            texture,
+            scale,
+            is_open: true,
+        }
+    }
+    fn id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+    fn present(&mut self, rgb: &[u8]) {
+        if !self.is_open {
+            return;
+        }
+        let row_size = self.texture.query().width as usize * 3;
+        self.texture.update(None, rgb, row_size).unwrap();
+        self.canvas.copy(&self.texture, None, None).unwrap();
+        self.canvas.present();
+    }
+    fn close(&mut self) {
+        self.is_open = false;
+        self.canvas.window_mut().hide();
+    }
+}
+
+struct DebugWindows {
+    nametables: DebugWindow,
+    pattern_tables: DebugWindow,
+    sprites: DebugWindow,
+    palettes: DebugWindow,
+    pattern_palette: u8,
+}
+
+impl DebugWindows {
+    fn new(video_subsystem: &VideoSubsystem) -> DebugWindows {
+        DebugWindows {
+            nametables: DebugWindow::new(
+                video_subsystem,
+                "Nametables",
+                NAMETABLES_WIDTH,
+                NAMETABLES_HEIGHT,
+                1,
+            ),
+            pattern_tables: DebugWindow::new(
+                video_subsystem,
+                "Pattern tables",
+                PATTERN_TABLES_WIDTH,
+                PATTERN_TABLES_HEIGHT,
+                3,
+            ),
+            sprites: DebugWindow::new(video_subsystem, "Sprites", SPRITES_WIDTH, SPRITES_HEIGHT, 4),
+            palettes: DebugWindow::new(
+                video_subsystem,
+                "Palettes",
+                PALETTES_WIDTH,
+                PALETTES_HEIGHT,
+                2,
+            ),
+            pattern_palette: 0,
+        }
+    }
+    fn present(&mut self, ppu: &Ppu) {
+        self.nametables.present(&render_nametables(ppu));
+        self.pattern_tables
+            .present(&render_pattern_tables(ppu, self.pattern_palette));
+        self.sprites.present(&render_sprites(ppu));
+        self.palettes.present(&render_palettes(ppu));
+    }
+    fn close(&mut self, window_id: u32) {
+        for window in [
+            &mut self.nametables,
+            &mut self.pattern_tables,
+            &mut self.sprites,
+            &mut self.palettes,
+        ]
+        .iter_mut()
+        {
+            if window.id() == window_id {
+                window.close();
+            }
+        }
+    }
+}
+
+fn start_av_recording(st: &mut GlobalState, nes: &Nes) {
+    st.av_start_frame = None;
+    match AvRecorder::create(&st.av_basename, nes.region()) {
+        Ok(recorder) => {
+            eprintln!(
+                "Recording to {}.y4m and {}.wav",
+                st.av_basename, st.av_basename
+            );
+            st.av_recorder = Some(recorder);
+        }
+        Err(e) => eprintln!("Unable to start recording {} {:?}", st.av_basename, e),
+    }
+}
+
+fn stop_av_recording(st: &mut GlobalState) {
+    if let Some(recorder) = st.av_recorder.take() {
+        let num_frames = recorder.num_frames();
+        match recorder.finish() {
+            Ok(()) => eprintln!("Recorded {} frames", num_frames),
+            Err(e) => eprintln!("Unable to finish recording {:?}", e),
+        }
+    }
+}
+
+fn start_gif_capture(st: &mut GlobalState, nes: &Nes) {
+    let filename = format!("capture-{}.gif", nes.ppu.current_frame());
+    let encoder = File::create(&filename).and_then(|file| {
+        GifEncoder::new(
+            BufWriter::new(file),
+            nes.ppu.palette(),
+            nes.region(),
+            st.gif_params,
+        )
+    });
+    match encoder {
+        Ok(encoder) => {
+            eprintln!("Capturing {}", filename);
+            st.gif_encoder = Some(encoder);
+        }
+        Err(e) => eprintln!("Unable to start capturing {} {:?}", filename, e),
+    }
+}
+
+fn stop_gif_capture(st: &mut GlobalState) {
+    if let Some(encoder) = st.gif_encoder.take() {
+        let num_frames = encoder.num_frames();
+        match encoder.finish() {
+            Ok(_) => eprintln!("Captured {} GIF frames", num_frames),
+            Err(e) => eprintln!("Unable to finish GIF {:?}", e),
+        }
+    }
+}
+
+// The controller ports start with joysticks, until Inputs::connect plugs in the real devices.
+fn create_nes() -> Nes {
+    let joystick1 = Box::new(Joystick::new());
+    let joystick2 = Box::new(Joystick::new());
     //let filename = "roms/donkey_kong.nes";
     let filename = "roms/mario.nes";
     match read_ines(filename.to_string()) {
@@ -403,8 +917,52 @@ fn create_nes(joystick1: Box<dyn AddressSpace>, joystick2: Box<dyn AddressSpace>
     }
 }
 
-fn present_frame(canvas: &mut Canvas<Window>, texture: &mut Texture, ppu_pixels: &[u8]) {
-    texture.update(None, ppu_pixels, RENDER_WIDTH * 3).unwrap();
+fn set_mouse_button(inputs: &mut Inputs, is_pressed: bool) {
+    for port in 0..2 {
+        if let Some(zapper) = inputs.zapper(port) {
+            zapper.set_trigger(is_pressed);
+        }
+        if let Some(arkanoid) = inputs.arkanoid(port) {
+            arkanoid.set_button(is_pressed);
+        }
+    }
+}
+
+fn get_power_pad_buttons(bindings: &[Vec<SdlInput>], keyboard: &KeyboardState) -> u16 {
+    let mut buttons = 0;
+    for (i, inputs) in bindings.iter().enumerate() {
+        if inputs
+            .iter()
+            .any(|input| input.is_pressed(keyboard, null_mut()))
+        {
+            buttons |= 1 << i;
+        }
+    }
+    buttons
+}
+
+// The screen pixel under a point in the main window, which is stretched to fit the window
+fn window_to_screen(canvas: &Canvas<Window>, x: i32, y: i32) -> Option<(u16, u16)> {
+    let (width, height) = canvas.window().size();
+    if x < 0 || y < 0 || width == 0 || height == 0 {
+        return None;
+    }
+    let screen_x = x as usize * RENDER_WIDTH / width as usize;
+    let screen_y = y as usize * RENDER_HEIGHT / height as usize;
+    Some((screen_x as u16, screen_y as u16))
+}
+
+fn present_frame(
+    canvas: &mut Canvas<Window>,
+    texture: &mut Texture,
+    ppu: &Ppu,
+    video: &mut VideoPipeline,
+) {
+    texture
+        .with_lock(None, |pixels, pitch| {
+            video.render_into(ppu, pixels, Some(pitch))
+        })
+        .unwrap();
     canvas.clear();
     canvas.copy(&texture, None, None).unwrap();
     canvas.present();
@@ -446,42 +1004,214 @@ impl AudioCallback for SquareWave {
     }
 }
 
-fn get_button_bit(controller: *mut GameController, button_id: u8) -> u8 {
-    // Button order: A,B, Select,Start,Up,Down,Left,Right
-    let button = match button_id {
-        0 => sdl2::controller::Button::A,
-        1 => sdl2::controller::Button::B,
-        2 => sdl2::controller::Button::Back,
-        3 => sdl2::controller::Button::Start,
-        4 => sdl2::controller::Button::DPadUp,
-        5 => sdl2::controller::Button::DPadDown,
-        6 => sdl2::controller::Button::DPadLeft,
-        7 => sdl2::controller::Button::DPadRight,
-        _ => panic!("Unknown button"),
-    };
-    unsafe {
-        match controller.as_ref() {
-            None => {
-                // eprintln!("DEBUG - ZERO");
-                0
+// In joystick bit order
+fn get_button_mask(
+    bindings: &[Vec<SdlInput>],
+    keyboard: &KeyboardState,
+    controller: *mut GameController,
+) -> u8 {
+    let mut button_mask = 0;
+    for (i, inputs) in bindings.iter().enumerate() {
+        if inputs
+            .iter()
+            .any(|input| input.is_pressed(keyboard, controller))
+        {
+            button_mask |= 1 << i;
+        }
+    }
+    button_mask
+}
+
+// A bound key, button or axis, resolved from its name
+enum SdlInput {
+    Key(Scancode),
+    Button(Button),
+    Axis(Axis, bool),
+}
+
+impl SdlInput {
+    // None for names SDL doesn't know
+    fn new(input: &BoundInput) -> Option<SdlInput> {
+        match input {
+            BoundInput::Key(name) => Keycode::from_name(name)
+                .and_then(Scancode::from_keycode)
+                .map(SdlInput::Key),
+            BoundInput::Button(name) => Button::from_string(name).map(SdlInput::Button),
+            BoundInput::Axis(name, is_positive) => {
+                Axis::from_string(name).map(|axis| SdlInput::Axis(axis, *is_positive))
             }
-            Some(controller) => {
-                //eprintln!("DEBUG - NOT ZERO");
-                controller.button(button) as u8
+        }
+    }
+    // Buttons and axes are read from `controller`, which is null when it isn't connected
+    fn is_pressed(&self, keyboard: &KeyboardState, controller: *mut GameController) -> bool {
+        let controller = unsafe { controller.as_ref() };
+        match (self, controller) {
+            (SdlInput::Key(scancode), _) => keyboard.is_scancode_pressed(*scancode),
+            (SdlInput::Button(button), Some(controller)) => controller.button(*button),
+            (SdlInput::Axis(axis, true), Some(controller)) => {
+                controller.axis(*axis) >= AXIS_THRESHOLD
             }
+            (SdlInput::Axis(axis, false), Some(controller)) => {
+                controller.axis(*axis) <= -AXIS_THRESHOLD
+            }
+            (_, None) => false,
         }
     }
 }
 
-fn get_button_mask(controller: *mut GameController) -> u8 {
-    let mut button_mask = 0;
-    button_mask |= get_button_bit(controller, 0) << 0;
-    button_mask |= get_button_bit(controller, 1) << 1;
-    button_mask |= get_button_bit(controller, 2) << 2;
-    button_mask |= get_button_bit(controller, 3) << 3;
-    button_mask |= get_button_bit(controller, 4) << 4;
-    button_mask |= get_button_bit(controller, 5) << 5;
-    button_mask |= get_button_bit(controller, 6) << 6;
-    button_mask |= get_button_bit(controller, 7) << 7;
-    return button_mask;
+struct SdlBindings {
+    controllers: Vec<[Vec<SdlInput>; 8]>,
+    turbo: Vec<[Vec<SdlInput>; 2]>,
+    power_pad: Vec<Vec<SdlInput>>,
+    hotkeys: Vec<(Hotkey, SdlInput)>,
+}
+
+impl SdlBindings {
+    fn new(bindings: &Bindings) -> SdlBindings {
+        // Unknown names and hotkeys on axes were already rejected by read_bindings
+        let resolve = |inputs: &Vec<BoundInput>| inputs.iter().filter_map(SdlInput::new).collect();
+        let mut hotkeys = Vec::new();
+        for (hotkey, inputs) in HOTKEYS.iter().zip(bindings.hotkeys.iter()) {
+            for input in inputs.iter().filter_map(SdlInput::new) {
+                if let SdlInput::Axis(..) = input {
+                    continue;
+                }
+                hotkeys.push((*hotkey, input));
+            }
+        }
+        SdlBindings {
+            controllers: bindings
+                .controllers
+                .iter()
+                .map(|buttons| {
+                    let mut controller: [Vec<SdlInput>; 8] = Default::default();
+                    for (inputs, bound) in controller.iter_mut().zip(buttons.iter()) {
+                        *inputs = resolve(bound);
+                    }
+                    controller
+                })
+                .collect(),
+            turbo: bindings
+                .turbo
+                .iter()
+                .map(|buttons| [resolve(&buttons[0]), resolve(&buttons[1])])
+                .collect(),
+            power_pad: bindings.power_pad.iter().map(resolve).collect(),
+            hotkeys,
+        }
+    }
+    // Hotkeys fire on key presses, and on button presses from any game controller
+    fn event_hotkey(&self, event: &Event) -> Option<Hotkey> {
+        self.hotkeys
+            .iter()
+            .find(|(_, input)| match (input, event) {
+                (
+                    SdlInput::Key(key),
+                    Event::KeyDown {
+                        scancode: Some(scancode),
+                        repeat: false,
+                        ..
+                    },
+                ) => key == scancode,
+                (
+                    SdlInput::Button(button),
+                    Event::ControllerButtonDown {
+                        button: pressed, ..
+                    },
+                ) => button == pressed,
+                _ => false,
+            })
+            .map(|(hotkey, _)| *hotkey)
+    }
+}
+
+// Reconnects the input devices the savestate was taken with, then loads it. Savestates from
+// other format versions are skipped.
+fn load_savestate(st: &mut GlobalState, nes: &mut Nes, filename: &str) {
+    let savestate = std::fs::read(filename).unwrap();
+    let result = savestate_input_config(&savestate).and_then(|config| {
+        if config != st.inputs.config {
+            st.inputs = Inputs::connect(nes, config);
+        }
+        nes.load_state(&mut savestate.as_slice())
+    });
+    if let Err(e) = result {
+        eprintln!("Not loading incompatible savestate {}: {}", filename, e);
+    }
+}
+
+fn run_hotkey(st: &mut GlobalState, nes: &mut Nes, tas: &mut Tas, hotkey: Hotkey) {
+    match hotkey {
+        Hotkey::SaveState => {
+            let mut file = File::create(DEFAULT_SAVESTATE).unwrap();
+            nes.save(&mut file);
+            let mut tas_file = File::create(DEFAULT_RECORDING).unwrap();
+            tas.save(&mut tas_file);
+        }
+        Hotkey::LoadState => {
+            load_savestate(st, nes, DEFAULT_SAVESTATE);
+            let mut tas_file = File::open(DEFAULT_RECORDING).unwrap();
+            tas.load(&mut tas_file);
+        }
+        // Play recording from initial state
+        Hotkey::PlayRecording => {
+            let mut tas_fh = File::open(DEFAULT_RECORDING).unwrap();
+            tas.load(&mut tas_fh);
+            load_savestate(st, nes, ROM_BEGIN_SAVESTATE);
+            st.tas_frame = 0;
+            nes.cpu.poke(0x075a, 3);
+        }
+        // Begin recording at current point
+        Hotkey::StartRecording => {
+            let mut ss_fh = File::create(ROM_BEGIN_SAVESTATE).unwrap();
+            nes.save(&mut ss_fh);
+            *tas = Tas::new();
+            st.tas_frame = 0;
+        }
+        Hotkey::Turbo => st.turbo_mode = !st.turbo_mode,
+        Hotkey::Pause => st.is_paused = !st.is_paused,
+        Hotkey::Debugger => nes.break_debugger(),
+        Hotkey::ToggleBackground => {
+            let mut layers = nes.ppu.layers();
+            layers.hide_background = !layers.hide_background;
+            nes.ppu.set_layers(layers);
+        }
+        Hotkey::ToggleSprites => {
+            let mut layers = nes.ppu.layers();
+            layers.hide_sprites = !layers.hide_sprites;
+            nes.ppu.set_layers(layers);
+        }
+        // Screenshot as displayed, or the native frame when Shift is held
+        Hotkey::Screenshot => {
+            let keymod = unsafe { (*st.sdl_context).keyboard().mod_state() };
+            let is_native = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+            let png = if is_native {
+                screenshot(&nes.ppu, None)
+            } else {
+                screenshot(&nes.ppu, Some(&mut st.video))
+            };
+            let filename = format!("screenshot-{}.png", nes.ppu.current_frame());
+            match write_png(&filename, &png) {
+                Ok(()) => eprintln!("Saved {}", filename),
+                Err(e) => eprintln!("Unable to save {} {:?}", filename, e),
+            }
+        }
+        // Start or stop audio/video recording, starting with the next frame
+        Hotkey::ToggleAvRecording => {
+            if st.av_recorder.is_some() {
+                stop_av_recording(st);
+            } else {
+                st.av_start_frame = Some(st.frames_run);
+                st.av_num_frames = None;
+            }
+        }
+        // Start or stop capturing a GIF, starting with the next frame
+        Hotkey::ToggleGif => {
+            if st.gif_encoder.is_some() {
+                stop_gif_capture(st);
+            } else {
+                start_gif_capture(st, nes);
+            }
+        }
+    }
 }
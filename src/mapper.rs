@@ -265,7 +265,7 @@ impl Mapper {
     }
 
     pub fn map_ram(&mut self, begin: u16, end: u16) {
-        let size = end - begin;
+        let size = end - begin + 1;
         let space: Ram = Ram {
             bs: vec![0; size as usize],
         };
@@ -6,7 +6,9 @@ use crate::apu::Apu;
 use crate::apu::ApuPort::*;
 use crate::c6502::C6502;
 use crate::common::*;
-use crate::joystick::Joystick;
+use crate::four_score::FourPlayerAdapter;
+use crate::input::{InputConfig, Inputs, PortDevice};
+use crate::joystick::{ControllerPorts, Joystick};
 use crate::mapper::*;
 use crate::mapper::{Mapper, Ram};
 use crate::ppu::CpuPpuInterconnect;
@@ -14,6 +16,7 @@ use crate::ppu::PaletteControl;
 use crate::ppu::Ppu;
 use crate::ppu::PpuPort;
 use crate::ppu::PpuPort::*;
+use crate::region::Region;
 use crate::serialization::Savable;
 
 use core::mem::transmute_copy;
@@ -23,11 +26,23 @@ use std::io;
 use std::io::Read;
 use std::io::Write;
 use std::ops::DerefMut;
+use std::ptr::null_mut;
 
 pub struct Nes {
     pub cpu: Box<C6502>,
     pub apu: Box<Apu>,
     pub ppu: Box<Ppu>,
+
+    region: Region,
+    // Kept when loading savestates, which otherwise restore the region they were taken in
+    region_override: Option<Region>,
+    controller_ports: *mut ControllerPorts, // Owned by the CPU's mapper
+    default_input: Option<InputConfig>,     // From the ROM header
+    input_config: InputConfig,              // What's in the controller ports
+    // Master clock timestamps of the next CPU and PPU cycles. The CPU and PPU are both driven by
+    // dividing down a single master clock: https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+    cpu_master_clock: u64,
+    ppu_master_clock: u64,
 }
 
 impl Nes {
@@ -36,6 +51,13 @@ impl Nes {
             cpu: Box::new(C6502::new(cpu_mapper)),
             apu: Box::new(Apu::new()),
             ppu: Box::new(Ppu::new()),
+            region: Region::Ntsc,
+            region_override: None,
+            controller_ports: null_mut(),
+            default_input: None,
+            input_config: InputConfig::default(),
+            cpu_master_clock: 0,
+            ppu_master_clock: 0,
         };
     }
 }
@@ -59,6 +81,8 @@ pub struct Ines {
     has_four_screen_vram: bool,
     is_vs_unisystem: bool,
     is_playchoice10: bool,
+    region: Region,
+    expansion_device: u8,
     prg_rom: HiddenBytes,
     chr_rom: HiddenBytes,
 }
@@ -97,6 +121,8 @@ pub fn read_ines(filename: String) -> Result<Ines, io::Error> {
         is_playchoice10: false, // TODO
         is_vs_unisystem: false, // TODO
         mapper: (header[6] >> 4) + ((header[7] >> 4) << 4),
+        region: read_region(&header),
+        expansion_device: read_expansion_device(&header),
         prg_rom: HiddenBytes(prg_rom),
         chr_rom: HiddenBytes(chr_rom),
     };
@@ -104,6 +130,38 @@ pub fn read_ines(filename: String) -> Result<Ines, io::Error> {
     return Ok(ret);
 }
 
+impl Ines {
+    pub fn region(&self) -> Region {
+        self.region
+    }
+    // The input devices the game expects, if the header says and they're emulated
+    pub fn default_input(&self) -> Option<InputConfig> {
+        InputConfig::from_expansion_device(self.expansion_device)
+    }
+}
+
+// https://wiki.nesdev.com/w/index.php/NES_2.0#Default_Expansion_Device
+fn read_expansion_device(header: &[u8; 16]) -> u8 {
+    let is_nes2 = (header[7] & 0x0C) == 0x08;
+    if !is_nes2 {
+        return 0;
+    }
+    header[15] & 0x3F
+}
+
+// https://wiki.nesdev.com/w/index.php/NES_2.0#CPU.2FPPU_Timing
+fn read_region(header: &[u8; 16]) -> Region {
+    let is_nes2 = (header[7] & 0x0C) == 0x08;
+    if !is_nes2 {
+        return Region::Ntsc;
+    }
+    match header[12] & 0x03 {
+        1 => Region::Pal,
+        3 => Region::Dendy,
+        _ => Region::Ntsc, // Multi-region ROMs run as NTSC
+    }
+}
+
 pub fn load_ines(
     rom: Ines,
     joystick1: Box<dyn AddressSpace>,
@@ -116,6 +174,8 @@ pub fn load_ines(
         let HiddenBytes(bytes) = rom.prg_rom;
         let cartridge = Rom::new(bytes);
         let mut mapper = Mapper::new();
+        // PRG-RAM. Test ROMs also report their results here.
+        mapper.map_ram(0x6000, 0x7FFF);
         match rom.num_prg_chunks {
             1 => mapper.map_mirrored(0x0000, 0x3FFF, 0x8000, 0xFFFF, Box::new(cartridge), true),
             2 => mapper.map_mirrored(0x0000, 0x7FFF, 0x8000, 0xFFFF, Box::new(cartridge), true),
@@ -123,44 +183,135 @@ pub fn load_ines(
         };
         mapper
     };
-    let ppu_mapper: Rom = {
+    let ppu_mapper: Box<dyn AddressSpace> = if rom.num_chr_chunks == 0 {
+        // Cartridges without CHR-ROM have 8KB of CHR-RAM instead.
+        Box::new(Ram::new(0x2000))
+    } else {
         let HiddenBytes(bytes) = rom.chr_rom;
-        let cartridge_ppu = Rom::new(bytes);
-        cartridge_ppu
+        Box::new(Rom::new(bytes))
     };
+    let region = rom.region;
+    let default_input = InputConfig::from_expansion_device(rom.expansion_device);
     let mut ret = Nes::new(Box::new(NullAddressSpace::new()));
+    ret.set_region(region);
+    ret.default_input = default_input;
     ret.map_nes_cpu(joystick1, joystick2, Box::new(cpu_mapper));
-    ret.map_nes_ppu(Box::new(ppu_mapper));
+    ret.map_nes_ppu(ppu_mapper);
     return ret;
 }
 
 impl Nes {
+    // Runs until the PPU finishes the current frame, stopping on the exact dot that starts the next one.
     pub fn run_frame(&mut self) {
-        run_clocks(self, 29780);
+        let frame = self.ppu.current_frame();
+        while self.ppu.current_frame() == frame {
+            self.step();
+        }
     }
     pub fn run_frame_headless(&mut self) {
-        let cpu_clocks_per_scanline = 114; // 113.667
-                                           // 0 and 241 are the pre-render and post-render scanlines
-        for _i in 0..241 {
+        let cpu_clocks_per_scanline = (self.region.cpu_clocks_per_frame()
+            / self.region.scanlines_per_frame() as f64)
+            .ceil() as u32; // 113.667 on NTSC
+        let vblank_scanline = self.region.vblank_scanline();
+        for _i in 0..vblank_scanline {
             run_clocks(&mut *self.cpu, cpu_clocks_per_scanline);
             // TODO: Signal on is_scanline_irq
         }
         // TODO: Vblank should only be triggered if rendering is enabled.
         self.cpu.nmi();
-        for _i in 242..261 {
+        for _i in vblank_scanline + 1..self.region.prerender_scanline() {
             run_clocks(&mut *self.cpu, cpu_clocks_per_scanline);
         }
     }
+    pub fn region(&self) -> Region {
+        self.region
+    }
+    // Sets the region and keeps it through later savestate loads, for a user's override
+    pub fn override_region(&mut self, region: Region) {
+        self.region_override = Some(region);
+        self.set_region(region);
+    }
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+    }
+    pub fn default_input(&self) -> Option<InputConfig> {
+        self.default_input
+    }
+    pub fn input_config(&self) -> InputConfig {
+        self.input_config
+    }
+    // Called by Inputs::connect, which plugs the devices in
+    pub(crate) fn set_input_config(&mut self, config: InputConfig) {
+        self.input_config = config;
+    }
+    // Loads a savestate taken with the connected input devices. The emulator is left untouched
+    // when the savestate is from another format version or other devices.
+    pub fn load_state(&mut self, fh: &mut dyn Read) -> Result<(), io::Error> {
+        let input_config = read_savestate_header(fh)?;
+        if input_config != self.input_config {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The savestate was taken with other input devices. Connect them before loading it",
+            ));
+        }
+        self.cpu.load(fh);
+        self.apu.load(fh);
+        self.ppu.load(fh);
+        let mut region = self.region;
+        region.load(fh);
+        self.set_region(self.region_override.unwrap_or(region));
+        self.cpu_master_clock.load(fh);
+        self.ppu_master_clock.load(fh);
+        let mut check = 0u32;
+        check.load(fh);
+        assert_eq!(check, 0xf00f);
+        Ok(())
+    }
+    // Plugs a device into controller port 0 ($4016) or 1 ($4017)
+    pub fn set_controller_port(&mut self, port: usize, device: Box<dyn AddressSpace>) {
+        assert!(!self.controller_ports.is_null(), "No controller ports");
+        unsafe { (*self.controller_ports).set_port(port, device) };
+    }
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.apu.reset();
+    }
     pub fn break_debugger(&mut self) {
         self.cpu.break_debugger();
     }
     pub fn current_frame(&self) -> u32 {
         return self.ppu.current_frame();
     }
+    // Runs whichever of the CPU or PPU is due next on the master clock. The CPU goes first on ties.
+    fn step(&mut self) {
+        if self.cpu_master_clock <= self.ppu_master_clock {
+            self.clock_cpu();
+            self.cpu_master_clock += self.region.master_clocks_per_cpu_clock();
+        } else {
+            self.ppu.clock();
+            self.ppu_master_clock += self.region.master_clocks_per_ppu_clock();
+        }
+    }
+    fn clock_cpu(&mut self) {
+        self.cpu.clock();
+        // Interrupts raised by the PPU during the previous cycle are taken only now, so a $2002
+        // read on this cycle can still suppress a VBlank NMI that was just raised.
+        if self.ppu.is_vblank_nmi {
+            //eprintln!("DEBUG - VBLANK-NMI DETECTED");
+            self.cpu.nmi();
+            self.ppu.is_vblank_nmi = false;
+        } else if self.ppu.is_scanline_irq {
+            self.cpu.irq();
+            self.ppu.is_scanline_irq = false;
+        }
+        self.apu.clock();
+    }
     fn map_nes_cpu(
         &mut self,
         joystick1: Box<dyn AddressSpace>,
-        _joystick2: Box<dyn AddressSpace>,
+        joystick2: Box<dyn AddressSpace>,
         cartridge: Box<dyn AddressSpace>,
     ) {
         let mut mapper: Mapper = Mapper::new();
@@ -168,6 +319,9 @@ impl Nes {
         let cpu_ppu: CpuPpuInterconnect =
             CpuPpuInterconnect::new(self.ppu.deref_mut(), self.cpu.deref_mut());
         let apu = self.apu.deref_mut() as *mut Apu;
+        let mut controller_ports =
+            Box::new(ControllerPorts::new(joystick1, joystick2, Box::new(apu)));
+        self.controller_ports = &mut *controller_ports;
         // https://wiki.nesdev.com/w/index.php/CPU_memory_map
         // NOTE: These are checked in-order, so put frequently-used components first
         mapper.map_address_space(0x4020, 0xFFFF, cartridge, true);
@@ -175,9 +329,7 @@ impl Nes {
         mapper.map_mirrored(0x2000, 0x2007, 0x2000, 0x3fff, Box::new(cpu_ppu), true);
         mapper.map_address_space(0x4000, 0x4013, Box::new(apu), true);
         mapper.map_address_space(0x4015, 0x4015, Box::new(apu), true);
-        mapper.map_address_space(0x4017, 0x4017, Box::new(apu), true); // TODO - 0x4017 is also mapped to joystick2
-        mapper.map_address_space(0x4017, 0x4017, _joystick2, false); // TODO -- Transfers ownership of joystick2 so it isn't deallocated
-        mapper.map_address_space(0x4016, 0x4016, joystick1, false);
+        mapper.map_address_space(0x4016, 0x4017, controller_ports, true);
         mapper.map_address_space(0x4014, 0x4014, Box::new(cpu_ppu), true);
         mapper.map_null(0x4018, 0x401F); // APU test mode
 
@@ -199,41 +351,68 @@ impl Nes {
     }
 }
 
+// One CPU cycle, along with the PPU dots that happen during it.
 impl Clocked for Nes {
     fn clock(&mut self) {
-        self.cpu.clock();
-        for _i in 1..3 {
-            self.ppu.clock();
+        let cpu_master_clock = self.cpu_master_clock;
+        while self.cpu_master_clock == cpu_master_clock
+            || self.ppu_master_clock < self.cpu_master_clock
+        {
+            self.step();
         }
-        if self.ppu.is_vblank_nmi {
-            //eprintln!("DEBUG - VBLANK-NMI DETECTED");
-            self.cpu.nmi();
-            self.ppu.is_vblank_nmi = false;
-        } else if self.ppu.is_scanline_irq {
-            self.cpu.irq();
-            self.ppu.is_scanline_irq = false;
-        }
-        self.apu.clock();
     }
 }
 
 use crate::serialization::file_position;
 
+// The connected input devices come first, so frontends can connect them before loading the rest.
+// Savestates start with a magic number and format version, so files from other versions of the
+// emulator are rejected instead of misread.
+const SAVESTATE_MAGIC: u32 = 0x5453_454e;
+const SAVESTATE_VERSION: u32 = 1;
+
 impl Savable for Nes {
     fn save(&self, fh: &mut dyn Write) {
+        SAVESTATE_MAGIC.save(fh);
+        SAVESTATE_VERSION.save(fh);
+        self.input_config.save(fh);
         self.cpu.save(fh);
         self.apu.save(fh);
         self.ppu.save(fh);
+        self.region.save(fh);
+        self.cpu_master_clock.save(fh);
+        self.ppu_master_clock.save(fh);
         0xF00Fu32.save(fh);
     }
     fn load(&mut self, fh: &mut dyn Read) {
-        self.cpu.load(fh);
-        self.apu.load(fh);
-        self.ppu.load(fh);
-        let mut check = 0u32;
-        check.load(fh);
-        assert_eq!(check, 0xf00f);
+        if let Err(e) = self.load_state(fh) {
+            eprintln!("Not loading incompatible savestate: {}", e);
+        }
+    }
+}
+
+fn read_savestate_header(fh: &mut dyn Read) -> Result<InputConfig, io::Error> {
+    let mut magic = 0u32;
+    magic.load(fh);
+    let mut version = 0u32;
+    version.load(fh);
+    if magic != SAVESTATE_MAGIC || version != SAVESTATE_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Expected a version {} savestate, got magic {:#x} version {}",
+                SAVESTATE_VERSION, magic, version
+            ),
+        ));
     }
+    let mut config = InputConfig::default();
+    config.load(fh);
+    Ok(config)
+}
+
+// The input devices a savestate was taken with, which must be connected before loading it
+pub fn savestate_input_config(savestate: &[u8]) -> Result<InputConfig, io::Error> {
+    read_savestate_header(&mut &savestate[..])
 }
 
 pub struct Tas {
@@ -265,3 +444,173 @@ impl Savable for Tas {
         self.inputs.load(fh);
     }
 }
+
+mod tests {
+    use super::*;
+
+    fn test_nes() -> Nes {
+        let mut nes = Nes::new(Box::new(Ram::new(0x10000)));
+        nes.ppu.mapper = Box::new(Ram::new(0x4000));
+        nes
+    }
+    #[test]
+    fn test_controller_ports() {
+        let mut nes = test_nes();
+        let mut joystick1 = Box::new(Joystick::new());
+        let mut joystick2 = Box::new(Joystick::new());
+        joystick1.set_buttons(0b01);
+        joystick2.set_buttons(0b11);
+        nes.map_nes_cpu(joystick1, joystick2, Box::new(Ram::new(0x10000)));
+        // One strobe latches both controllers
+        nes.cpu.poke(0x4016, 1);
+        nes.cpu.poke(0x4016, 0);
+        assert_eq!(nes.cpu.peek(0x4016) & 1, 1);
+        assert_eq!(nes.cpu.peek(0x4017) & 1, 1);
+        assert_eq!(nes.cpu.peek(0x4016) & 1, 0);
+        assert_eq!(nes.cpu.peek(0x4017) & 1, 1);
+        // $4017 writes go to the APU, without strobing port 2
+        nes.cpu.poke(0x4017, 1);
+        assert_eq!(nes.cpu.peek(0x4017) & 1, 0);
+    }
+    fn test_controller_nes() -> Nes {
+        let mut nes = test_nes();
+        let joystick1 = Box::new(Joystick::new());
+        let joystick2 = Box::new(Joystick::new());
+        nes.map_nes_cpu(joystick1, joystick2, Box::new(Ram::new(0x10000)));
+        nes
+    }
+    #[test]
+    fn test_savestate_input_config() {
+        let power_pad = InputConfig {
+            ports: [PortDevice::Joystick, PortDevice::PowerPad],
+            four_players: None,
+        };
+        let four_score = InputConfig {
+            four_players: Some(FourPlayerAdapter::FourScore),
+            ..InputConfig::default()
+        };
+        // Taken with joysticks, and loaded where a Power Pad was connected
+        let mut nes = test_controller_nes();
+        let mut state = Vec::new();
+        nes.save(&mut state);
+        let mut other = test_controller_nes();
+        Inputs::connect(&mut other, power_pad);
+        assert_eq!(
+            savestate_input_config(&state).unwrap(),
+            InputConfig::default()
+        );
+        Inputs::connect(&mut other, savestate_input_config(&state).unwrap());
+        other.load_state(&mut state.as_slice()).unwrap();
+        assert_eq!(other.input_config(), InputConfig::default());
+        // Taken with a Four Score, and loaded where there were only joysticks
+        Inputs::connect(&mut nes, four_score);
+        let mut state = Vec::new();
+        nes.save(&mut state);
+        assert_eq!(savestate_input_config(&state).unwrap(), four_score);
+        Inputs::connect(&mut other, savestate_input_config(&state).unwrap());
+        other.load_state(&mut state.as_slice()).unwrap();
+        assert_eq!(other.input_config(), four_score);
+    }
+    #[test]
+    fn test_savestate_input_mismatch() {
+        let mut state = Vec::new();
+        test_controller_nes().save(&mut state);
+        let mut nes = test_controller_nes();
+        let config = InputConfig {
+            ports: [PortDevice::Joystick, PortDevice::PowerPad],
+            four_players: None,
+        };
+        Inputs::connect(&mut nes, config);
+        let e = nes.load_state(&mut state.as_slice()).unwrap_err();
+        assert!(e.to_string().contains("other input devices"), "{}", e);
+        assert_eq!(nes.input_config(), config);
+    }
+    #[test]
+    fn test_savestate_header() {
+        let mut state = Vec::new();
+        test_controller_nes().save(&mut state);
+        // A savestate from before the header, which started with its input devices
+        let old_state = &state[8..];
+        assert!(savestate_input_config(old_state).is_err());
+        assert!(savestate_input_config(&[]).is_err());
+        let mut nes = test_controller_nes();
+        let e = nes.load_state(&mut &old_state[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        // A newer format version
+        state[4] += 1;
+        assert!(savestate_input_config(&state).is_err());
+    }
+    #[test]
+    fn test_controller_open_bus() {
+        let mut nes = test_nes();
+        let mut joystick1 = Box::new(Joystick::new());
+        joystick1.set_buttons(0b101);
+        nes.map_nes_cpu(
+            joystick1,
+            Box::new(Joystick::new()),
+            Box::new(Ram::new(0x10000)),
+        );
+        nes.cpu.poke(0x4016, 1);
+        nes.cpu.poke(0x4016, 0);
+        let reads: Vec<u8> = (0..10).map(|_| nes.cpu.peek(0x4016)).collect();
+        assert_eq!(
+            reads,
+            vec![0x41, 0x40, 0x41, 0x40, 0x40, 0x40, 0x40, 0x40, 0x41, 0x41]
+        );
+        assert_eq!(nes.cpu.peek(0x4017), 0x40);
+    }
+    #[test]
+    fn test_three_dots_per_cpu_clock() {
+        let mut nes = test_nes();
+        nes.clock();
+        assert_eq!(nes.ppu.current_cycle(), 3);
+        run_clocks(&mut nes, 113);
+        assert_eq!(nes.ppu.current_scanline(), 1);
+        assert_eq!(nes.ppu.current_cycle(), 1);
+    }
+    #[test]
+    fn test_run_frame_stops_at_frame_boundary() {
+        let mut nes = test_nes();
+        for frame in 1..4 {
+            nes.run_frame();
+            assert_eq!(nes.current_frame(), frame);
+            assert_eq!(nes.ppu.current_scanline(), 0);
+            assert_eq!(nes.ppu.current_cycle(), 0);
+        }
+        // 341 * 262 dots per frame, at 3 dots per CPU cycle
+        assert_eq!(nes.cpu_master_clock / 12, 89342);
+    }
+    #[test]
+    fn test_region_override() {
+        let mut state = Vec::new();
+        test_nes().save(&mut state);
+        let mut nes = test_nes();
+        nes.override_region(Region::Pal);
+        nes.load(&mut state.as_slice());
+        assert_eq!(nes.region(), Region::Pal);
+    }
+    #[test]
+    fn test_pal_clock_ratio() {
+        let mut nes = test_nes();
+        nes.set_region(Region::Pal);
+        // 3.2 dots per CPU cycle
+        run_clocks(&mut nes, 5);
+        assert_eq!(nes.ppu.current_cycle(), 16);
+        nes.run_frame();
+        assert_eq!(nes.ppu.current_scanline(), 0);
+        assert_eq!(nes.ppu.current_cycle(), 0);
+        assert_eq!(nes.cpu_master_clock / 16, 33248);
+    }
+    #[test]
+    fn test_read_region() {
+        let mut header = [0x4E, 0x45, 0x53, 0x1A, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(read_region(&header), Region::Ntsc);
+        header[12] = 1;
+        // iNES 1.0 headers don't have a region field
+        assert_eq!(read_region(&header), Region::Ntsc);
+        header[7] = 0x08;
+        assert_eq!(read_region(&header), Region::Pal);
+        header[12] = 3;
+        assert_eq!(read_region(&header), Region::Dendy);
+    }
+}
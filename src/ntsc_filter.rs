@@ -0,0 +1,291 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use crate::palette::{
+    normalized_ntsc_signal, subcarrier_angle, yiq_to_rgb, NtscPaletteParams, SUBCARRIER_PHASES,
+};
+use crate::ppu::{write_rgb_pixel, PixelFormat, Ppu, RENDER_HEIGHT, RENDER_WIDTH};
+
+use std::str::FromStr;
+
+// Simulates the PPU's composite video signal and a TV decoding it, instead of looking colors up
+// directly. https://wiki.nesdev.com/w/index.php/NTSC_video
+//
+// Each PPU dot is 8 samples of the signal, and the color subcarrier repeats every 12 samples.
+// A scanline is 341 dots, so each one starts 4 samples later in the subcarrier than the last.
+// That produces the diagonal "dot crawl" pattern, which alternates between two phases on
+// successive frames because odd frames are one dot shorter.
+pub const NTSC_WIDTH: usize = RENDER_WIDTH * 2;
+pub const NTSC_HEIGHT: usize = RENDER_HEIGHT;
+const SAMPLES_PER_PIXEL: usize = 8;
+const SAMPLES_PER_LINE: usize = RENDER_WIDTH * SAMPLES_PER_PIXEL;
+const SAMPLES_PER_OUTPUT_PIXEL: usize = SAMPLES_PER_LINE / NTSC_WIDTH;
+const SCANLINE_PHASE_SHIFT: usize = 4;
+const ODD_FRAME_PHASE_SHIFT: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NtscPreset {
+    Composite, // Luma and chroma share a wire, so each bleeds into the other
+    SVideo,    // Separate luma and chroma wires, but chroma is still low-bandwidth
+    Rgb,       // No signal decoding at all
+}
+
+impl FromStr for NtscPreset {
+    type Err = String;
+    fn from_str(s: &str) -> Result<NtscPreset, String> {
+        match s.to_lowercase().as_str() {
+            "composite" => Ok(NtscPreset::Composite),
+            "svideo" | "s-video" => Ok(NtscPreset::SVideo),
+            "rgb" => Ok(NtscPreset::Rgb),
+            _ => Err(format!(
+                "Unknown NTSC preset {:?}. Expected composite, svideo or rgb",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NtscFilterParams {
+    pub preset: NtscPreset,
+    pub picture: NtscPaletteParams,
+    // Samples averaged to decode luma. 12 removes the subcarrier completely. Fewer is sharper,
+    // but leaves some of the subcarrier in as dot crawl.
+    pub luma_window: usize,
+    // Samples averaged to decode chroma. More blurs colors further into their neighbors.
+    pub chroma_window: usize,
+}
+
+impl NtscFilterParams {
+    pub fn new(preset: NtscPreset) -> NtscFilterParams {
+        let (luma_window, chroma_window) = match preset {
+            NtscPreset::Composite => (8, 24),
+            NtscPreset::SVideo => (4, 24),
+            NtscPreset::Rgb => (1, 1),
+        };
+        NtscFilterParams {
+            preset,
+            picture: NtscPaletteParams::default(),
+            luma_window,
+            chroma_window,
+        }
+    }
+}
+
+pub struct NtscFilter {
+    params: NtscFilterParams,
+    // Normalized signal level of each ExtendedPalette index at each subcarrier phase
+    signals: Vec<[f64; SUBCARRIER_PHASES]>,
+    // Average level of each ExtendedPalette index, which is all an S-Video luma wire carries
+    lumas: Vec<f64>,
+    cos: [f64; SUBCARRIER_PHASES],
+    sin: [f64; SUBCARRIER_PHASES],
+    // Running sums of the demodulated signal over a scanline, so any window is a subtraction.
+    y_sums: Vec<f64>,
+    i_sums: Vec<f64>,
+    q_sums: Vec<f64>,
+}
+
+impl NtscFilter {
+    pub fn new(params: NtscFilterParams) -> NtscFilter {
+        let mut signals = vec![[0.0; SUBCARRIER_PHASES]; 512];
+        let mut lumas = vec![0.0; 512];
+        for (pixel, signal) in signals.iter_mut().enumerate() {
+            for (phase, level) in signal.iter_mut().enumerate() {
+                *level = normalized_ntsc_signal(pixel as u16, phase);
+            }
+            lumas[pixel] = signal.iter().sum::<f64>() / SUBCARRIER_PHASES as f64;
+        }
+        let mut cos = [0.0; SUBCARRIER_PHASES];
+        let mut sin = [0.0; SUBCARRIER_PHASES];
+        for phase in 0..SUBCARRIER_PHASES {
+            let angle = subcarrier_angle(phase, params.picture.hue);
+            cos[phase] = angle.cos();
+            sin[phase] = angle.sin();
+        }
+        NtscFilter {
+            params,
+            signals,
+            lumas,
+            cos,
+            sin,
+            y_sums: vec![0.0; SAMPLES_PER_LINE + 1],
+            i_sums: vec![0.0; SAMPLES_PER_LINE + 1],
+            q_sums: vec![0.0; SAMPLES_PER_LINE + 1],
+        }
+    }
+    pub fn params(&self) -> &NtscFilterParams {
+        &self.params
+    }
+    // Writes the filtered frame, NTSC_WIDTH x NTSC_HEIGHT, into `buf` like Ppu::render_into.
+    pub fn render_into(
+        &mut self,
+        ppu: &Ppu,
+        buf: &mut [u8],
+        format: PixelFormat,
+        stride: Option<usize>,
+    ) {
+        let frame_phase = ODD_FRAME_PHASE_SHIFT * (ppu.current_frame() % 2) as usize;
+        self.render_with_phase(ppu, buf, format, stride, frame_phase);
+    }
+    fn render_with_phase(
+        &mut self,
+        ppu: &Ppu,
+        buf: &mut [u8],
+        format: PixelFormat,
+        stride: Option<usize>,
+        frame_phase: usize,
+    ) {
+        assert!(
+            format != PixelFormat::PaletteIndex,
+            "The NTSC filter only outputs RGB"
+        );
+        let bytes_per_pixel = format.bytes_per_pixel();
+        let row_size = NTSC_WIDTH * bytes_per_pixel;
+        let stride = stride.unwrap_or(row_size);
+        assert!(
+            stride >= row_size,
+            "Stride {} is smaller than a row",
+            stride
+        );
+        assert!(
+            buf.len() >= stride * (NTSC_HEIGHT - 1) + row_size,
+            "Buffer of {} bytes is too small for a frame with stride {}",
+            buf.len(),
+            stride
+        );
+        for y in 0..NTSC_HEIGHT {
+            let line_phase = (frame_phase + y * SCANLINE_PHASE_SHIFT) % SUBCARRIER_PHASES;
+            let row = &mut buf[y * stride..y * stride + row_size];
+            if self.params.preset == NtscPreset::Rgb {
+                self.render_rgb_line(ppu, y, row, format);
+            } else {
+                self.decode_line(ppu, y, line_phase);
+                self.render_decoded_line(row, format);
+            }
+        }
+    }
+
+    fn pixel(ppu: &Ppu, x: usize, y: usize) -> usize {
+        let i = y * RENDER_WIDTH + x;
+        ((ppu.display_emphasis[i] as usize) << 6) | (ppu.display[i] as usize & 0x3F)
+    }
+
+    fn decode_line(&mut self, ppu: &Ppu, y: usize, line_phase: usize) {
+        let is_separated = self.params.preset == NtscPreset::SVideo;
+        for s in 0..SAMPLES_PER_LINE {
+            let pixel = NtscFilter::pixel(ppu, s / SAMPLES_PER_PIXEL, y);
+            let phase = (line_phase + s) % SUBCARRIER_PHASES;
+            let signal = self.signals[pixel][phase];
+            let (luma, chroma) = if is_separated {
+                (self.lumas[pixel], signal - self.lumas[pixel])
+            } else {
+                (signal, signal)
+            };
+            self.y_sums[s + 1] = self.y_sums[s] + luma;
+            self.i_sums[s + 1] = self.i_sums[s] + chroma * self.cos[phase];
+            self.q_sums[s + 1] = self.q_sums[s] + chroma * self.sin[phase];
+        }
+    }
+
+    fn render_decoded_line(&self, row: &mut [u8], format: PixelFormat) {
+        let bytes_per_pixel = format.bytes_per_pixel();
+        for (x, out) in row.chunks_exact_mut(bytes_per_pixel).enumerate() {
+            let center = x * SAMPLES_PER_OUTPUT_PIXEL + SAMPLES_PER_OUTPUT_PIXEL / 2;
+            let yiq = (
+                window_mean(&self.y_sums, center, self.params.luma_window),
+                window_mean(&self.i_sums, center, self.params.chroma_window),
+                window_mean(&self.q_sums, center, self.params.chroma_window),
+            );
+            write_rgb_pixel(out, format, yiq_to_rgb(yiq, &self.params.picture));
+        }
+    }
+
+    fn render_rgb_line(&self, ppu: &Ppu, y: usize, row: &mut [u8], format: PixelFormat) {
+        let bytes_per_pixel = format.bytes_per_pixel();
+        let palette = ppu.palette();
+        for (x, out) in row.chunks_exact_mut(bytes_per_pixel).enumerate() {
+            let pixel = NtscFilter::pixel(ppu, x * RENDER_WIDTH / NTSC_WIDTH, y);
+            write_rgb_pixel(out, format, palette[pixel]);
+        }
+    }
+}
+
+// Mean of the samples in a `width`-wide window around `center`, clipped to the scanline.
+fn window_mean(sums: &[f64], center: usize, width: usize) -> f64 {
+    let lo = center.saturating_sub(width / 2);
+    let hi = (lo + width).min(sums.len() - 1);
+    (sums[hi] - sums[lo]) / (hi - lo) as f64
+}
+
+mod tests {
+    use super::*;
+    use crate::palette::generate_ntsc_palette;
+
+    const NTSC_SIZE: usize = NTSC_WIDTH * NTSC_HEIGHT * 3;
+
+    fn filter_frame_with_phase(ppu: &Ppu, preset: NtscPreset, frame_phase: usize) -> Vec<u8> {
+        let mut filter = NtscFilter::new(NtscFilterParams::new(preset));
+        let mut buf = vec![0; NTSC_SIZE];
+        filter.render_with_phase(ppu, &mut buf, PixelFormat::Rgb24, None, frame_phase);
+        buf
+    }
+    fn filter_frame(ppu: &Ppu, preset: NtscPreset) -> Vec<u8> {
+        filter_frame_with_phase(ppu, preset, 0)
+    }
+    fn rgb_at(buf: &[u8], x: usize, y: usize) -> (u8, u8, u8) {
+        let i = (y * NTSC_WIDTH + x) * 3;
+        (buf[i], buf[i + 1], buf[i + 2])
+    }
+    fn is_close((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> bool {
+        let close = |a: u8, b: u8| (a as i32 - b as i32).abs() <= 1;
+        close(r1, r2) && close(g1, g2) && close(b1, b2)
+    }
+
+    #[test]
+    fn test_svideo_solid_color() {
+        let mut ppu = Ppu::new();
+        ppu.display = [0x16; RENDER_WIDTH * RENDER_HEIGHT];
+        let buf = filter_frame(&ppu, NtscPreset::SVideo);
+        let expected = generate_ntsc_palette(&NtscPaletteParams::default())[0x16];
+        assert!(is_close(rgb_at(&buf, 100, 100), expected));
+    }
+    #[test]
+    fn test_rgb_uses_ppu_palette() {
+        let mut ppu = Ppu::new();
+        ppu.display[3] = 0x21;
+        let buf = filter_frame(&ppu, NtscPreset::Rgb);
+        assert_eq!(rgb_at(&buf, 6, 0), ppu.palette()[0x21]);
+        assert_eq!(rgb_at(&buf, 7, 0), ppu.palette()[0x21]);
+        assert_eq!(rgb_at(&buf, 8, 0), ppu.palette()[0x00]);
+    }
+    #[test]
+    fn test_composite_artifacts() {
+        // A fine grey checkerboard picks up false color on a composite signal, but not on S-Video.
+        let mut ppu = Ppu::new();
+        for (i, pixel) in ppu.display.iter_mut().enumerate() {
+            *pixel = if i % 2 == 0 { 0x30 } else { 0x0F };
+        }
+        let is_grey = |(r, g, b): (u8, u8, u8)| r == g && g == b;
+        assert!(!is_grey(rgb_at(
+            &filter_frame(&ppu, NtscPreset::Composite),
+            100,
+            100
+        )));
+        let svideo = filter_frame(&ppu, NtscPreset::SVideo);
+        assert!(
+            is_grey(rgb_at(&svideo, 100, 100)),
+            "{:?}",
+            rgb_at(&svideo, 100, 100)
+        );
+    }
+    #[test]
+    fn test_dot_crawl() {
+        // The subcarrier left in composite luma moves between frames.
+        let mut ppu = Ppu::new();
+        ppu.display = [0x16; RENDER_WIDTH * RENDER_HEIGHT];
+        let even = filter_frame_with_phase(&ppu, NtscPreset::Composite, 0);
+        let odd = filter_frame_with_phase(&ppu, NtscPreset::Composite, ODD_FRAME_PHASE_SHIFT);
+        assert_ne!(even, odd);
+    }
+}
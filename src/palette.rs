@@ -0,0 +1,192 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use crate::common::ternary;
+use crate::ppu::{extend_palette, ExtendedPalette, RgbColor, SystemPalette};
+
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+// .pal files are a list of RGB triples: either the 64 system colors, or all 512 combinations of
+// color and PPUMASK emphasis bits in the same order as ExtendedPalette.
+const PALETTE_FILE_SIZE: usize = 64 * 3;
+const EXTENDED_PALETTE_FILE_SIZE: usize = 512 * 3;
+
+pub fn read_palette(filename: String) -> Result<ExtendedPalette, io::Error> {
+    let mut file = File::open(filename)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    parse_palette(&bytes)
+}
+
+pub fn parse_palette(bytes: &[u8]) -> Result<ExtendedPalette, io::Error> {
+    let read_color = |i: usize| -> RgbColor { (bytes[i * 3], bytes[i * 3 + 1], bytes[i * 3 + 2]) };
+    match bytes.len() {
+        PALETTE_FILE_SIZE => {
+            let mut palette: SystemPalette = [(0, 0, 0); 64];
+            for (i, color) in palette.iter_mut().enumerate() {
+                *color = read_color(i);
+            }
+            Ok(extend_palette(&palette))
+        }
+        EXTENDED_PALETTE_FILE_SIZE => {
+            let mut palette: ExtendedPalette = [(0, 0, 0); 512];
+            for (i, color) in palette.iter_mut().enumerate() {
+                *color = read_color(i);
+            }
+            Ok(palette)
+        }
+        n => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Palette files should be {} or {} bytes. Found {}",
+                PALETTE_FILE_SIZE, EXTENDED_PALETTE_FILE_SIZE, n
+            ),
+        )),
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NtscPaletteParams {
+    pub hue: f64,        // Degrees
+    pub saturation: f64, // 1.0 = unchanged
+    pub contrast: f64,   // 1.0 = unchanged
+    pub brightness: f64, // Added to the luma. 0.0 = unchanged
+}
+
+impl Default for NtscPaletteParams {
+    fn default() -> NtscPaletteParams {
+        NtscPaletteParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+        }
+    }
+}
+
+// Signal voltages for each luma level, when the wave is low or high.
+// https://wiki.nesdev.com/w/index.php/NTSC_video
+const SIGNAL_LOW: [f64; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f64; 4] = [1.094, 1.506, 1.962, 1.962];
+const SIGNAL_BLACK: f64 = 0.518;
+const SIGNAL_WHITE: f64 = 1.962;
+const SIGNAL_EMPHASIS_ATTENUATION: f64 = 0.746;
+// The color subcarrier is 12 half-dots long
+pub const SUBCARRIER_PHASES: usize = 12;
+// Rotates the decoded hue so colors land near their commonly-used RGB values
+const HUE_OFFSET_DEGREES: f64 = 120.0;
+
+// Decodes the composite signal the PPU generates for every color and emphasis combination.
+pub fn generate_ntsc_palette(params: &NtscPaletteParams) -> ExtendedPalette {
+    let mut ret = [(0, 0, 0); 512];
+    for (pixel, rgb) in ret.iter_mut().enumerate() {
+        *rgb = yiq_to_rgb(decode_ntsc_pixel(pixel as u16, params.hue), params);
+    }
+    ret
+}
+
+pub fn yiq_to_rgb((y, i, q): (f64, f64, f64), params: &NtscPaletteParams) -> RgbColor {
+    let y = y * params.contrast + params.brightness;
+    let i = i * params.saturation * params.contrast;
+    let q = q * params.saturation * params.contrast;
+    // https://en.wikipedia.org/wiki/YIQ
+    (
+        to_rgb_channel(y + 0.946882 * i + 0.623557 * q),
+        to_rgb_channel(y - 0.274788 * i - 0.635691 * q),
+        to_rgb_channel(y - 1.108545 * i + 1.709007 * q),
+    )
+}
+
+// `pixel` is an ExtendedPalette index: emphasis << 6 | color
+pub fn decode_ntsc_pixel(pixel: u16, hue: f64) -> (f64, f64, f64) {
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..SUBCARRIER_PHASES {
+        let signal = normalized_ntsc_signal(pixel, phase);
+        let angle = subcarrier_angle(phase, hue);
+        y += signal;
+        i += signal * angle.cos();
+        q += signal * angle.sin();
+    }
+    let n = SUBCARRIER_PHASES as f64;
+    (y / n, i / n, q / n)
+}
+
+// The voltage the PPU outputs for `pixel` at a given phase of the color subcarrier
+pub fn ntsc_signal(pixel: u16, phase: usize) -> f64 {
+    let color = (pixel & 0x0F) as usize;
+    let level = ternary(color >= 0x0E, 1, ((pixel >> 4) & 0x03) as usize);
+    let emphasis = (pixel >> 6) & 0x07;
+    let is_high = match color {
+        0x00 => true,
+        0x0D..=0x0F => false,
+        _ => is_in_color_phase(color, phase),
+    };
+    let signal = ternary(is_high, SIGNAL_HIGH[level], SIGNAL_LOW[level]);
+    // Each emphasis bit darkens the part of the wave that lines up with its color.
+    let is_attenuated = color < 0x0E
+        && ((emphasis & 0b001 > 0 && is_in_color_phase(0x0C, phase))
+            || (emphasis & 0b010 > 0 && is_in_color_phase(0x04, phase))
+            || (emphasis & 0b100 > 0 && is_in_color_phase(0x08, phase)));
+    ternary(is_attenuated, signal * SIGNAL_EMPHASIS_ATTENUATION, signal)
+}
+
+// Scales the signal so black is 0.0 and white is 1.0
+pub fn normalized_ntsc_signal(pixel: u16, phase: usize) -> f64 {
+    (ntsc_signal(pixel, phase) - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+// The angle a decoder's reference oscillator is at during `phase`
+pub fn subcarrier_angle(phase: usize, hue: f64) -> f64 {
+    PI * phase as f64 / 6.0 + (hue + HUE_OFFSET_DEGREES).to_radians()
+}
+
+fn is_in_color_phase(color: usize, phase: usize) -> bool {
+    (color + phase) % SUBCARRIER_PHASES < 6
+}
+
+fn to_rgb_channel(x: f64) -> u8 {
+    (x.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_palette() {
+        let bytes: Vec<u8> = (0..PALETTE_FILE_SIZE).map(|x| x as u8).collect();
+        let palette = parse_palette(&bytes).unwrap();
+        assert_eq!(palette[1], (3, 4, 5));
+        // Emphasis variants are generated from the base colors
+        assert_eq!(
+            palette[(7 << 6) | 1],
+            extend_palette(&[(3, 4, 5); 64])[(7 << 6) | 1]
+        );
+
+        let bytes: Vec<u8> = (0..EXTENDED_PALETTE_FILE_SIZE).map(|x| x as u8).collect();
+        let palette = parse_palette(&bytes).unwrap();
+        assert_eq!(palette[(1 << 6) | 1], (195, 196, 197));
+
+        assert!(parse_palette(&[0; 100]).is_err());
+    }
+    #[test]
+    fn test_generate_ntsc_palette() {
+        let palette = generate_ntsc_palette(&NtscPaletteParams::default());
+        let is_reddest = |(r, g, b): RgbColor| r > g && r > b;
+        let is_greenest = |(r, g, b): RgbColor| g > r && g > b;
+        let is_bluest = |(r, g, b): RgbColor| b > r && b > g;
+        assert!(is_bluest(palette[0x12]), "{:?}", palette[0x12]);
+        assert!(is_reddest(palette[0x16]), "{:?}", palette[0x16]);
+        assert!(is_greenest(palette[0x1A]), "{:?}", palette[0x1A]);
+        assert_eq!(palette[0x0F], (0, 0, 0));
+        assert_eq!(palette[0x20], (255, 255, 255));
+        // Greys have no chroma
+        let (r, g, b) = palette[0x10];
+        assert!(r == g && g == b, "{:?}", palette[0x10]);
+        // Red emphasis darkens the other channels of white
+        let (r, g, b) = palette[(1 << 6) | 0x20];
+        assert!(r > g && r > b, "{:?}", (r, g, b));
+    }
+}
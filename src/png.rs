@@ -0,0 +1,345 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use crate::ppu::{PixelFormat, Ppu, RENDER_HEIGHT, RENDER_SIZE, RENDER_WIDTH};
+use crate::video::VideoPipeline;
+
+use std::fs::File;
+use std::io;
+use std::io::Write;
+
+// Encodes the current frame as a PNG. With a pipeline, the frame goes through its filter and
+// scaler first; otherwise it's the native 256x240 image.
+pub fn screenshot(ppu: &Ppu, video: Option<&mut VideoPipeline>) -> Vec<u8> {
+    match video {
+        None => {
+            let mut rgb = vec![0; RENDER_SIZE];
+            ppu.render_into(&mut rgb, PixelFormat::Rgb24, None);
+            encode_png(RENDER_WIDTH, RENDER_HEIGHT, &rgb)
+        }
+        Some(video) => {
+            let (width, height) = video.output_size();
+            encode_png(width, height, &video.render(ppu))
+        }
+    }
+}
+
+pub fn write_png(filename: &str, png: &[u8]) -> Result<(), io::Error> {
+    File::create(filename)?.write_all(png)
+}
+
+// https://www.w3.org/TR/PNG/
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const COLOR_TYPE_RGB: u8 = 2;
+
+// `rgb` is an RGB24 image without padding between rows
+pub fn encode_png(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let row_size = width * 3;
+    assert!(rgb.len() >= row_size * height, "Image is too small");
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, deflate, no filtering beyond the row filters, no interlacing
+    ihdr.extend_from_slice(&[8, COLOR_TYPE_RGB, 0, 0, 0]);
+    // Every row is prefixed with filter type 0, which leaves it unchanged.
+    let mut scanlines = Vec::with_capacity((row_size + 1) * height);
+    for row in rgb[..row_size * height].chunks(row_size) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &ihdr);
+    write_chunk(&mut png, b"IDAT", &zlib_compress(&scanlines));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 > 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// https://tools.ietf.org/html/rfc1950
+fn zlib_compress(bytes: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    out.extend(deflate(bytes));
+    out.extend_from_slice(&adler32(bytes).to_be_bytes());
+    out
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in bytes {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+// A single deflate block using the fixed Huffman codes, with greedy LZ77 matching. NES frames
+// are mostly long runs of a few colors, so this gets most of the way to a full encoder.
+// https://tools.ietf.org/html/rfc1951
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+const HASH_SIZE: usize = 1 << 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit_buffer: u32,
+    num_bits: u8,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            bit_buffer: 0,
+            num_bits: 0,
+        }
+    }
+    // Least significant bit first, as deflate packs everything but Huffman codes
+    fn write_bits(&mut self, value: u32, num_bits: u8) {
+        self.bit_buffer |= value << self.num_bits;
+        self.num_bits += num_bits;
+        while self.num_bits >= 8 {
+            self.bytes.push(self.bit_buffer as u8);
+            self.bit_buffer >>= 8;
+            self.num_bits -= 8;
+        }
+    }
+    // Huffman codes are packed starting from their most significant bit
+    fn write_code(&mut self, code: u32, num_bits: u8) {
+        let reversed = code.reverse_bits() >> (32 - num_bits as u32);
+        self.write_bits(reversed, num_bits);
+    }
+    fn finish(mut self) -> Vec<u8> {
+        if self.num_bits > 0 {
+            self.bytes.push(self.bit_buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+fn write_literal(w: &mut BitWriter, literal: u16) {
+    let literal = literal as u32;
+    match literal {
+        0..=143 => w.write_code(0x30 + literal, 8),
+        144..=255 => w.write_code(0x190 + literal - 144, 9),
+        256..=279 => w.write_code(literal - 256, 7),
+        _ => w.write_code(0xC0 + literal - 280, 8),
+    }
+}
+
+fn write_match(w: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE
+        .iter()
+        .rposition(|base| *base as usize <= length)
+        .unwrap();
+    write_literal(w, 257 + code as u16);
+    w.write_bits(
+        (length - LENGTH_BASE[code] as usize) as u32,
+        LENGTH_EXTRA_BITS[code],
+    );
+    let code = DISTANCE_BASE
+        .iter()
+        .rposition(|base| *base as usize <= distance)
+        .unwrap();
+    w.write_code(code as u32, 5);
+    w.write_bits(
+        (distance - DISTANCE_BASE[code] as usize) as u32,
+        DISTANCE_EXTRA_BITS[code],
+    );
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let x = (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize;
+    (x.wrapping_mul(2_654_435_761) >> 7) % HASH_SIZE
+}
+
+fn deflate(bytes: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    // BFINAL = 1, BTYPE = 01(fixed Huffman codes)
+    w.write_bits(0b011, 3);
+    // The most recent position with each hash, and the previous position with the same hash
+    let mut head = vec![usize::MAX; HASH_SIZE];
+    let mut prev = vec![usize::MAX; bytes.len()];
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let (length, distance) = longest_match(bytes, pos, &head, &prev);
+        if length >= MIN_MATCH {
+            write_match(&mut w, length, distance);
+            for i in pos..pos + length {
+                insert(bytes, &mut head, &mut prev, i);
+            }
+            pos += length;
+        } else {
+            write_literal(&mut w, bytes[pos] as u16);
+            insert(bytes, &mut head, &mut prev, pos);
+            pos += 1;
+        }
+    }
+    write_literal(&mut w, 256);
+    w.finish()
+}
+
+fn insert(bytes: &[u8], head: &mut [usize], prev: &mut [usize], pos: usize) {
+    if pos + MIN_MATCH <= bytes.len() {
+        let h = hash(&bytes[pos..]);
+        prev[pos] = head[h];
+        head[h] = pos;
+    }
+}
+
+fn longest_match(bytes: &[u8], pos: usize, head: &[usize], prev: &[usize]) -> (usize, usize) {
+    if pos + MIN_MATCH > bytes.len() {
+        return (0, 0);
+    }
+    let max_length = MAX_MATCH.min(bytes.len() - pos);
+    let (mut best_length, mut best_distance) = (0, 0);
+    let mut candidate = head[hash(&bytes[pos..])];
+    for _ in 0..MAX_CHAIN {
+        if candidate == usize::MAX || pos - candidate > WINDOW_SIZE {
+            break;
+        }
+        let length = (0..max_length)
+            .take_while(|i| bytes[candidate + i] == bytes[pos + i])
+            .count();
+        if length > best_length {
+            best_length = length;
+            best_distance = pos - candidate;
+            if length == max_length {
+                break;
+            }
+        }
+        candidate = prev[candidate];
+    }
+    (best_length, best_distance)
+}
+
+mod tests {
+    use super::*;
+
+    // Decodes the fixed-Huffman blocks `deflate` writes
+    fn inflate(bytes: &[u8]) -> Vec<u8> {
+        let mut bit = 0;
+        let mut read_bits = |n: usize| -> usize {
+            let mut x = 0;
+            for i in 0..n {
+                x |= ((bytes[bit / 8] >> (bit % 8)) as usize & 1) << i;
+                bit += 1;
+            }
+            x
+        };
+        assert_eq!(read_bits(3), 0b011);
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            // Reads a Huffman code one bit at a time, most significant bit first
+            let mut code = 0;
+            for _ in 0..7 {
+                code = code << 1 | read_bits(1);
+            }
+            let literal = if code <= 0x17 {
+                code + 256
+            } else {
+                code = code << 1 | read_bits(1);
+                if (0x30..=0xBF).contains(&code) {
+                    code - 0x30
+                } else if (0xC0..=0xC7).contains(&code) {
+                    code - 0xC0 + 280
+                } else {
+                    code = code << 1 | read_bits(1);
+                    code - 0x190 + 144
+                }
+            };
+            match literal {
+                0..=255 => out.push(literal as u8),
+                256 => return out,
+                _ => {
+                    let i = literal - 257;
+                    let length = LENGTH_BASE[i] as usize + read_bits(LENGTH_EXTRA_BITS[i] as usize);
+                    let mut i = 0;
+                    for _ in 0..5 {
+                        i = i << 1 | read_bits(1);
+                    }
+                    let distance =
+                        DISTANCE_BASE[i] as usize + read_bits(DISTANCE_EXTRA_BITS[i] as usize);
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_deflate_round_trip() {
+        let mut bytes: Vec<u8> = (0..=255).collect();
+        bytes.extend(vec![7; 1000]);
+        bytes.extend((0..5000).map(|x| (x % 300 / 7) as u8));
+        bytes.extend(b"abcabcabd");
+        let compressed = deflate(&bytes);
+        assert!(compressed.len() < bytes.len() / 4, "{}", compressed.len());
+        assert_eq!(inflate(&compressed), bytes);
+        assert_eq!(inflate(&deflate(&[])), Vec::<u8>::new());
+    }
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+    #[test]
+    fn test_encode_png() {
+        let rgb: Vec<u8> = (0..4 * 2 * 3).map(|x| x as u8).collect();
+        let png = encode_png(4, 2, &rgb);
+        assert_eq!(png[..8], SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(png[16..24], [0, 0, 0, 4, 0, 0, 0, 2]);
+        assert_eq!(png[png.len() - 8..png.len() - 4], *b"IEND");
+        // The zlib stream in IDAT holds each row behind its filter byte
+        let idat_length = u32::from_be_bytes([png[33], png[34], png[35], png[36]]) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let zlib = &png[41..41 + idat_length];
+        let scanlines = inflate(&zlib[2..zlib.len() - 4]);
+        assert_eq!(scanlines[0], 0);
+        assert_eq!(scanlines[1..13], rgb[..12]);
+        assert_eq!(scanlines[13], 0);
+        assert_eq!(scanlines[14..], rgb[12..]);
+    }
+}
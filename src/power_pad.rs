@@ -0,0 +1,123 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use core::cell::Cell;
+use std::io::Read;
+use std::io::Write;
+
+use crate::common::get_bit;
+use crate::joystick::{DATA_LINE_D3, DATA_LINE_D4};
+use crate::mapper::AddressSpace;
+use crate::serialization::Savable;
+
+// https://wiki.nesdev.com/w/index.php/Power_Pad
+// The 12 buttons are numbered as on side B of the mat, which is also the Family Trainer's
+// layout. A strobe latches them into two shift registers, read in this order on D3 and D4.
+const D3_BUTTONS: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const D4_BUTTONS: [u8; 4] = [4, 3, 12, 8];
+pub const POWER_PAD_NUM_BUTTONS: u8 = 12;
+
+pub struct PowerPad {
+    buttons: u16, // Bit n-1 is button n
+    d3_register: Cell<u8>,
+    d4_register: Cell<u8>,
+    strobe_active: bool,
+}
+
+impl Savable for PowerPad {
+    fn save(&self, fh: &mut dyn Write) {
+        self.d3_register.get().save(fh);
+        self.d4_register.get().save(fh);
+        self.strobe_active.save(fh);
+    }
+    fn load(&mut self, fh: &mut dyn Read) {
+        let mut register = 0;
+        register.load(fh);
+        self.d3_register.set(register);
+        register.load(fh);
+        self.d4_register.set(register);
+        self.strobe_active.load(fh);
+    }
+}
+
+impl Default for PowerPad {
+    fn default() -> Self {
+        PowerPad::new()
+    }
+}
+
+impl PowerPad {
+    pub fn new() -> PowerPad {
+        PowerPad {
+            buttons: 0,
+            d3_register: Cell::new(0),
+            d4_register: Cell::new(0),
+            strobe_active: false,
+        }
+    }
+    // Bit n-1 is button n
+    pub fn set_buttons(&mut self, buttons: u16) {
+        self.buttons = buttons;
+    }
+    fn latch(&self) {
+        let is_pressed = |button: u8| (self.buttons >> (button - 1)) as u8 & 1;
+        let d3 = D3_BUTTONS
+            .iter()
+            .enumerate()
+            .fold(0, |register, (i, &button)| {
+                register | is_pressed(button) << i
+            });
+        let d4 = D4_BUTTONS
+            .iter()
+            .enumerate()
+            .fold(0xF0, |register, (i, &button)| {
+                register | is_pressed(button) << i
+            });
+        self.d3_register.set(d3);
+        self.d4_register.set(d4);
+    }
+}
+
+impl AddressSpace for PowerPad {
+    // Both registers shift in 1s, so reads past the end report every button as pressed.
+    fn peek(&self, _ptr: u16) -> u8 {
+        if self.strobe_active {
+            self.latch();
+        }
+        let (d3, d4) = (self.d3_register.get(), self.d4_register.get());
+        self.d3_register.set((d3 >> 1) | 0x80);
+        self.d4_register.set((d4 >> 1) | 0x80);
+        let mut data = 0;
+        if get_bit(d3, 0) > 0 {
+            data |= DATA_LINE_D3;
+        }
+        if get_bit(d4, 0) > 0 {
+            data |= DATA_LINE_D4;
+        }
+        data
+    }
+    fn poke(&mut self, _ptr: u16, v: u8) {
+        self.strobe_active = get_bit(v, 0) > 0;
+        if self.strobe_active {
+            self.latch();
+        }
+    }
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_power_pad() {
+        let mut power_pad = PowerPad::new();
+        // Buttons 1, 9 and 12
+        power_pad.set_buttons(0b1001_0000_0001);
+        power_pad.poke(0x4016, 1);
+        power_pad.poke(0x4016, 0);
+        let reads: Vec<u8> = (0..9).map(|_| power_pad.peek(0x4017)).collect();
+        let d3: Vec<u8> = reads.iter().map(|data| get_bit(*data, 3)).collect();
+        let d4: Vec<u8> = reads.iter().map(|data| get_bit(*data, 4)).collect();
+        assert_eq!(d3, vec![0, 1, 0, 1, 0, 0, 0, 0, 1]);
+        assert_eq!(d4, vec![0, 0, 1, 0, 1, 1, 1, 1, 1]);
+    }
+}
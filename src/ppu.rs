@@ -5,6 +5,7 @@
 use crate::c6502::C6502;
 use crate::common::*;
 use crate::mapper::{AddressSpace, Mapper};
+use crate::region::Region;
 use crate::serialization::Savable;
 
 use std::io::Read;
@@ -30,10 +31,8 @@ const ADDRESS_UNIVERSAL_BACKGROUND_COLOR: u16 = 0x3f00;
 const ADDRESS_BACKGROUND_PALETTE0: u16 = 0x3f00;
 const SPRITE_HEIGHT: u8 = 8;
 const SPRITE_WIDTH: u8 = 8;
-const SCANLINE_PRERENDER: u16 = 261;
 const SCANLINE_RENDER: u16 = 0;
 const SCANLINE_POSTRENDER: u16 = 240;
-const SCANLINE_VBLANK: u16 = 241;
 const GLOBAL_BACKGROUND_COLOR: PaletteColor = PaletteColor { color: 0 };
 
 pub const RENDER_WIDTH: usize = 256;
@@ -41,14 +40,80 @@ pub const RENDER_HEIGHT: usize = 240;
 pub const UNRENDER_SIZE: usize = RENDER_WIDTH * RENDER_HEIGHT;
 pub const RENDER_SIZE: usize = UNRENDER_SIZE * 3;
 
+// Layouts render_into() can write. Names give the byte order in memory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb24,
+    Rgba8888,
+    Bgra8888,
+    PaletteIndex, // The system color from `display`, without emphasis
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Rgba8888 | PixelFormat::Bgra8888 => 4,
+            PixelFormat::PaletteIndex => 1,
+        }
+    }
+}
+
+// Writes one pixel of an RGB `format` into `out`, which is bytes_per_pixel() long.
+pub fn write_rgb_pixel(out: &mut [u8], format: PixelFormat, (r, g, b): RgbColor) {
+    match format {
+        PixelFormat::Rgb24 => out.copy_from_slice(&[r, g, b]),
+        PixelFormat::Rgba8888 => out.copy_from_slice(&[r, g, b, 0xFF]),
+        PixelFormat::Bgra8888 => out.copy_from_slice(&[b, g, r, 0xFF]),
+        PixelFormat::PaletteIndex => panic!("Palette indices aren't an RGB format"),
+    }
+}
+
+// Layers hidden from the display. They don't affect anything the game can observe, like
+// sprite 0 hits or sprite overflow.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct LayerMask {
+    pub hide_background: bool,
+    pub hide_sprites: bool,
+    pub hidden_sprites: u64, // Bit n hides OAM entry n
+}
+
+impl LayerMask {
+    pub fn is_sprite_hidden(&self, oam_index: u8) -> bool {
+        self.hide_sprites || (self.hidden_sprites >> oam_index) & 1 > 0
+    }
+    pub fn toggle_sprite(&mut self, oam_index: u8) {
+        self.hidden_sprites ^= 1 << oam_index;
+    }
+}
+
+impl Savable for LayerMask {
+    fn save(&self, fh: &mut dyn Write) {
+        self.hide_background.save(fh);
+        self.hide_sprites.save(fh);
+        self.hidden_sprites.save(fh);
+    }
+    fn load(&mut self, fh: &mut dyn Read) {
+        self.hide_background.load(fh);
+        self.hide_sprites.load(fh);
+        self.hidden_sprites.load(fh);
+    }
+}
+
 pub struct Ppu {
     pub display: [u8; UNRENDER_SIZE],
+    pub display_emphasis: [u8; UNRENDER_SIZE], // PPUMASK emphasis bits(BGR) for each pixel of display
     pub oam: [u8; 256],
     pub mapper: Box<dyn AddressSpace>,
     pub is_vblank_nmi: bool,
     pub is_scanline_irq: bool,
 
     registers: PpuRegisters,
+    region: Region, // Set by Nes, and not saved with the PPU
+    palette: ExtendedPalette,
+    layers: LayerMask,       // Set by the frontend, and not saved with the PPU
+    unlimited_sprites: bool, // Also set by the frontend
+    extra_sprites: Vec<LoadedSprite>, // In-range sprites past the first 8, for display only
     sprite_pattern_table: bool, // Is the sprite pattern table the 'right' one?
     background_pattern_table: bool, // Is the background pattern table the right one?
     sprite_overflow: bool,
@@ -62,8 +127,8 @@ pub struct Ppu {
 
     oam_ptr: u8,
 
-    clocks_until_nmi: u8,
     nmi_occurred: bool,
+    suppress_vblank: bool, // $2002 was read the dot before VBlank starts
     frame: u32,
     scanline: u16,
     cycle: u16,
@@ -81,11 +146,16 @@ pub struct Ppu {
     sprite_xs: [u8; 8],
     sprite_priorities: [bool; 8],
     sprite_indices: [u8; 8],
+    secondary_oam: [u8; 32],
+    secondary_oam_indices: [u8; 8], // Primary OAM index of each sprite copied into secondary OAM
+    oam_bus: u8,                    // Last value read or written by sprite evaluation
+    sprite_evaluation: SpriteEvaluation,
 }
 
 impl Savable for Ppu {
     fn save(&self, fh: &mut dyn Write) {
         self.display.save(fh);
+        self.display_emphasis.save(fh);
         self.oam.save(fh);
         self.mapper.save(fh);
         self.is_vblank_nmi.save(fh);
@@ -102,8 +172,8 @@ impl Savable for Ppu {
         self.generate_vblank_nmi.save(fh);
         self.ppudata_buffer.save(fh);
         self.oam_ptr.save(fh);
-        self.clocks_until_nmi.save(fh);
         self.nmi_occurred.save(fh);
+        self.suppress_vblank.save(fh);
         self.frame.save(fh);
         self.scanline.save(fh);
         self.cycle.save(fh);
@@ -120,9 +190,14 @@ impl Savable for Ppu {
         self.sprite_xs.save(fh);
         self.sprite_priorities.save(fh);
         self.sprite_indices.save(fh);
+        self.secondary_oam.save(fh);
+        self.secondary_oam_indices.save(fh);
+        self.oam_bus.save(fh);
+        self.sprite_evaluation.save(fh);
     }
     fn load(&mut self, fh: &mut dyn Read) {
         self.display.load(fh);
+        self.display_emphasis.load(fh);
         self.oam.load(fh);
         self.mapper.load(fh);
         self.is_vblank_nmi.load(fh);
@@ -139,8 +214,8 @@ impl Savable for Ppu {
         self.generate_vblank_nmi.load(fh);
         self.ppudata_buffer.load(fh);
         self.oam_ptr.load(fh);
-        self.clocks_until_nmi.load(fh);
         self.nmi_occurred.load(fh);
+        self.suppress_vblank.load(fh);
         self.frame.load(fh);
         self.scanline.load(fh);
         self.cycle.load(fh);
@@ -157,6 +232,10 @@ impl Savable for Ppu {
         self.sprite_xs.load(fh);
         self.sprite_priorities.load(fh);
         self.sprite_indices.load(fh);
+        self.secondary_oam.load(fh);
+        self.secondary_oam_indices.load(fh);
+        self.oam_bus.load(fh);
+        self.sprite_evaluation.load(fh);
     }
 }
 
@@ -341,10 +420,11 @@ impl PpuRegisters {
         self.w = !self.w;
         // eprintln!("DEBUG - PPU WRITE ADDRESS - {:x} {}", self.v, self.w);
     }
-    fn is_rendering(&self) -> bool {
+    // The pre-render scanline depends on the region, so the PPU passes it in.
+    fn is_rendering(&self, prerender_scanline: u16) -> bool {
         let scanline = self.scanline();
         return self.is_rendering_enabled()
-            && ((scanline == SCANLINE_PRERENDER) || scanline < SCANLINE_POSTRENDER);
+            && ((scanline == prerender_scanline) || scanline < SCANLINE_POSTRENDER);
     }
     pub fn vram_ptr(&self) -> u16 {
         return self.v;
@@ -358,10 +438,15 @@ impl PpuRegisters {
     pub fn is_rendering_enabled(&self) -> bool {
         return self.sprites_enabled || self.background_enabled;
     }
+    pub fn emphasis(&self) -> u8 {
+        (self.emphasize_red as u8)
+            | (self.emphasize_green as u8) << 1
+            | (self.emphasize_blue as u8) << 2
+    }
 
-    fn advance_vram_ptr(&mut self) {
+    fn advance_vram_ptr(&mut self, prerender_scanline: u16) {
         // TODO - VRAM ptr is supposed to increment in a weird way during rendering.
-        if self.is_rendering() && false {
+        if self.is_rendering(prerender_scanline) && false {
             self.increment_x();
             self.increment_y();
         } else {
@@ -387,6 +472,14 @@ impl PpuRegisters {
         return ((self.v & 0b1111100000) >> 5) as u8;
     }
 
+    // From t, which holds the scroll the game set for the next frame
+    pub fn scroll(&self) -> (u16, u16) {
+        let t = self.t;
+        let x = ((t >> 10) & 1) * 256 + (t & 0x1F) * 8 + self.x as u16;
+        let y = ((t >> 11) & 1) * 240 + ((t >> 5) & 0x1F) * 8 + ((t >> 12) & 0x7);
+        (x, y)
+    }
+
     pub fn tile_address(&self) -> u16 {
         return ADDRESS_NAMETABLE0 | (self.v & 0x0FFF);
     }
@@ -430,6 +523,105 @@ struct Sprite {
     flip_vertical: bool,
 }
 
+// https://wiki.nesdev.com/w/index.php/PPU_OAM
+fn decode_sprite(index: u8, bytes: &[u8]) -> Sprite {
+    let attribute = bytes[2];
+    Sprite {
+        index,
+        y: bytes[0],
+        tile_index: bytes[1],
+        palette: (attribute & 3) + 4,
+        is_front: get_bit(attribute, 5) == 0,
+        flip_horizontal: get_bit(attribute, 6) > 0,
+        flip_vertical: get_bit(attribute, 7) > 0,
+        x: bytes[3],
+    }
+}
+
+// A loaded sprite. Those past the first 8 on a scanline are only kept when unlimited sprites
+// are enabled.
+#[derive(Copy, Clone, Debug)]
+struct LoadedSprite {
+    index: u8,
+    x: u8,
+    pattern: u16,
+    palette: u8,
+    is_front: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum SpriteEvaluationState {
+    FindY,        // Checking whether sprite n is on the next scanline
+    CopySprite,   // Copying the rest of an in-range sprite into secondary OAM
+    Overflow,     // Secondary OAM is full; looking for a 9th sprite
+    OverflowCopy, // Found a 9th sprite; reading the rest of it
+    Done,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct SpriteEvaluation {
+    n: u8,               // Sprite in primary OAM
+    m: u8,               // Byte within the sprite
+    secondary_index: u8, // Next free byte in secondary OAM
+    remaining: u8,       // Bytes left to read in OverflowCopy
+    state: SpriteEvaluationState,
+}
+
+impl SpriteEvaluation {
+    fn new() -> SpriteEvaluation {
+        SpriteEvaluation {
+            n: 0,
+            m: 0,
+            secondary_index: 0,
+            remaining: 0,
+            state: SpriteEvaluationState::FindY,
+        }
+    }
+    fn next_sprite(&mut self) {
+        self.n += 1;
+        self.state = if self.n == 64 {
+            self.n = 0;
+            SpriteEvaluationState::Done
+        } else if self.secondary_index == 32 {
+            SpriteEvaluationState::Overflow
+        } else {
+            SpriteEvaluationState::FindY
+        };
+    }
+    fn increment_m_with_carry(&mut self) {
+        self.m += 1;
+        if self.m == 4 {
+            self.m = 0;
+            self.n = (self.n + 1) & 63;
+        }
+    }
+}
+
+impl Savable for SpriteEvaluation {
+    fn save(&self, fh: &mut dyn Write) {
+        self.n.save(fh);
+        self.m.save(fh);
+        self.secondary_index.save(fh);
+        self.remaining.save(fh);
+        (self.state as u8).save(fh);
+    }
+    fn load(&mut self, fh: &mut dyn Read) {
+        self.n.load(fh);
+        self.m.load(fh);
+        self.secondary_index.load(fh);
+        self.remaining.load(fh);
+        let mut state = 0u8;
+        state.load(fh);
+        self.state = match state {
+            0 => SpriteEvaluationState::FindY,
+            1 => SpriteEvaluationState::CopySprite,
+            2 => SpriteEvaluationState::Overflow,
+            3 => SpriteEvaluationState::OverflowCopy,
+            _ => SpriteEvaluationState::Done,
+        };
+    }
+}
+
 /* a=ABCDEFGH, b=12345678, combine_bitplanes(a,b) = A1B2C3D4E5F6G7H8 */
 fn combine_bitplanes(mut a: u8, mut b: u8) -> u16 {
     let mut out = 0u16;
@@ -588,7 +780,7 @@ impl Clocked for Ppu {
 
         let is_visible_line = self.scanline < 240;
         let is_visible_cycle = self.cycle >= 1 && self.cycle <= 256;
-        let is_fetch_line = self.scanline == SCANLINE_PRERENDER || is_visible_line;
+        let is_fetch_line = self.scanline == self.region.prerender_scanline() || is_visible_line;
         let is_prefetch_cycle = self.cycle >= 321 && self.cycle <= 336;
         let is_fetch_cycle = is_prefetch_cycle || is_visible_cycle;
 
@@ -609,7 +801,10 @@ impl Clocked for Ppu {
                     _ => {}
                 }
             }
-            if self.scanline == SCANLINE_PRERENDER && self.cycle >= 280 && self.cycle <= 304 {
+            if self.scanline == self.region.prerender_scanline()
+                && self.cycle >= 280
+                && self.cycle <= 304
+            {
                 self.registers.copy_y();
             }
             if is_fetch_line {
@@ -627,20 +822,34 @@ impl Clocked for Ppu {
 
         // Sprite logic
         if self.is_rendering_enabled() {
-            if self.cycle == 257 {
-                if is_visible_line {
-                    self.evaluate_sprites();
-                } else {
-                    self.sprite_count = 0;
+            if is_visible_line {
+                match self.cycle {
+                    1..=64 => self.clear_secondary_oam(),
+                    65..=256 => self.evaluate_sprites(),
+                    _ => {}
                 }
             }
+            if is_fetch_line && self.cycle >= 257 && self.cycle <= 320 {
+                if self.cycle == 257 {
+                    if is_visible_line {
+                        self.load_sprites();
+                    } else {
+                        self.sprite_count = 0;
+                    }
+                }
+                self.fetch_secondary_oam();
+            }
         }
         // Vblank
-        if self.scanline == 241 && self.cycle == 1 {
+        if self.scanline == self.region.vblank_scanline() && self.cycle == 1 {
             // eprintln!("DEBUG - VBLANK HIT - {}", self.generate_vblank_nmi);
-            self.set_vblank(true);
+            if self.suppress_vblank {
+                self.suppress_vblank = false;
+            } else {
+                self.set_vblank(true);
+            }
         }
-        if self.scanline == SCANLINE_PRERENDER && self.cycle == 1 {
+        if self.scanline == self.region.prerender_scanline() && self.cycle == 1 {
             self.set_vblank(false);
             self.sprite0_hit = false;
             self.sprite_overflow = false;
@@ -687,12 +896,18 @@ impl Ppu {
         let mapper = Mapper::new();
         Ppu {
             display: [0; UNRENDER_SIZE],
+            display_emphasis: [0; UNRENDER_SIZE],
             oam: [0; 256],
             mapper: Box::new(mapper),
             is_vblank_nmi: false,
             is_scanline_irq: false,
 
             registers: PpuRegisters::new(),
+            region: Region::Ntsc,
+            palette: extend_palette(&SYSTEM_PALETTE),
+            layers: LayerMask::default(),
+            unlimited_sprites: false,
+            extra_sprites: Vec::new(),
             sprite_pattern_table: false,
             background_pattern_table: false,
             sprite_overflow: false,
@@ -706,8 +921,8 @@ impl Ppu {
 
             oam_ptr: 0,
 
-            clocks_until_nmi: 0,
             nmi_occurred: false,
+            suppress_vblank: false,
             frame: 0,
             scanline: 0,
             cycle: 0,
@@ -724,28 +939,101 @@ impl Ppu {
             sprite_xs: [0; 8],
             sprite_priorities: [false; 8],
             sprite_indices: [0; 8],
+            secondary_oam: [0xFF; 32],
+            secondary_oam_indices: [0; 8],
+            oam_bus: 0,
+            sprite_evaluation: SpriteEvaluation::new(),
         }
     }
 
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+    // Colors used by render(). See crate::palette for loading or generating one.
+    pub fn set_palette(&mut self, palette: ExtendedPalette) {
+        self.palette = palette;
+    }
+    pub fn palette(&self) -> &ExtendedPalette {
+        &self.palette
+    }
+    pub fn set_layers(&mut self, layers: LayerMask) {
+        self.layers = layers;
+    }
+    pub fn layers(&self) -> LayerMask {
+        self.layers
+    }
+    // Draws every sprite on a scanline instead of the first 8. Sprite overflow, sprite 0 hits
+    // and everything else the game can observe still behave as if the limit were there.
+    pub fn set_unlimited_sprites(&mut self, unlimited_sprites: bool) {
+        self.unlimited_sprites = unlimited_sprites;
+        self.extra_sprites.clear();
+    }
+    pub fn is_unlimited_sprites(&self) -> bool {
+        self.unlimited_sprites
+    }
+    // Where the next frame starts drawing from, in pixels across all 4 nametables
+    pub fn scroll(&self) -> (u16, u16) {
+        self.registers.scroll()
+    }
+    pub fn background_pattern_table_address(&self) -> u16 {
+        ternary(self.background_pattern_table, 0x1000, 0x0000)
+    }
+    pub fn sprite_pattern_table_address(&self) -> u16 {
+        ternary(self.sprite_pattern_table, 0x1000, 0x0000)
+    }
+    pub fn is_tall_sprites(&self) -> bool {
+        self.sprite_size
+    }
     pub fn current_frame(&self) -> u32 {
         return self.frame;
     }
+    pub fn current_scanline(&self) -> u16 {
+        return self.scanline;
+    }
+    pub fn current_cycle(&self) -> u16 {
+        return self.cycle;
+    }
 
     pub fn render(&self) -> [u8; RENDER_SIZE] {
         let mut ret = [0; RENDER_SIZE];
-        for i in 0..UNRENDER_SIZE {
-            let c = self.display[i];
-            let (r, g, b) = self.lookup_system_pixel(c);
-            ret[i * 3 + 0] = r;
-            ret[i * 3 + 1] = g;
-            ret[i * 3 + 2] = b;
-        }
+        self.render_into(&mut ret, PixelFormat::Rgb24, None);
         return ret;
     }
 
-    // pub fn render(&self, buf: &mut [u8]) {
-    //     buf.copy_from_slice(&self.display[0..RENDER_SIZE]);
-    // }
+    // Writes the current frame into `buf`. Rows start `stride` bytes apart, or are packed
+    // together if there's no stride.
+    pub fn render_into(&self, buf: &mut [u8], format: PixelFormat, stride: Option<usize>) {
+        let bytes_per_pixel = format.bytes_per_pixel();
+        let row_size = RENDER_WIDTH * bytes_per_pixel;
+        let stride = stride.unwrap_or(row_size);
+        assert!(
+            stride >= row_size,
+            "Stride {} is smaller than a row",
+            stride
+        );
+        assert!(
+            buf.len() >= stride * (RENDER_HEIGHT - 1) + row_size,
+            "Buffer of {} bytes is too small for a frame with stride {}",
+            buf.len(),
+            stride
+        );
+        for y in 0..RENDER_HEIGHT {
+            let row = &mut buf[y * stride..y * stride + row_size];
+            for (x, pixel) in row.chunks_exact_mut(bytes_per_pixel).enumerate() {
+                let i = y * RENDER_WIDTH + x;
+                let c = self.display[i];
+                if format == PixelFormat::PaletteIndex {
+                    pixel[0] = c;
+                    continue;
+                }
+                write_rgb_pixel(
+                    pixel,
+                    format,
+                    self.lookup_system_pixel(c, self.display_emphasis[i]),
+                );
+            }
+        }
+    }
 
     fn shift_new_tile(&mut self) {
         let background_tile = self.tile_nametable;
@@ -802,26 +1090,24 @@ impl Ppu {
     }
 
     fn tick_counters(&mut self) {
-        if self.clocks_until_nmi > 0 {
-            self.clocks_until_nmi -= 1;
-            if self.clocks_until_nmi == 0 && self.generate_vblank_nmi && self.nmi_occurred {
-                self.is_vblank_nmi = true;
-            }
-        }
-        if self.is_rendering_enabled() {
-            if self.frame_parity && self.scanline == 261 && self.cycle == 339 {
-                self.cycle = 0;
-                self.scanline = 0;
-                self.frame += 1;
-                self.frame_parity = !self.frame_parity;
-                return;
-            }
+        // The last dot of the pre-render line is skipped on odd frames while rendering.
+        if self.is_rendering_enabled()
+            && self.region.has_skipped_dot()
+            && self.frame_parity
+            && self.scanline == self.region.prerender_scanline()
+            && self.cycle == 339
+        {
+            self.cycle = 0;
+            self.scanline = 0;
+            self.frame += 1;
+            self.frame_parity = !self.frame_parity;
+            return;
         }
         self.cycle += 1;
         if self.cycle > 340 {
             self.cycle = 0;
             self.scanline += 1;
-            if self.scanline > 261 {
+            if self.scanline >= self.region.scanlines_per_frame() {
                 self.scanline = 0;
                 self.frame += 1;
                 self.frame_parity = !self.frame_parity;
@@ -832,54 +1118,78 @@ impl Ppu {
     fn render_pixel(&mut self) {
         let x = self.cycle - 1;
         let y = self.scanline;
-        let bg_color = self.background_pixel();
-        let (i, sprite_color) = self.sprite_pixel();
+        let mut bg_color = self.background_pixel();
+        let mut sprite = self.sprite_pixel(false, |_| true);
         // Sprite 0 test
-        if self.sprite_indices[i as usize] == 00 && sprite_color.is_opaque() && bg_color.is_opaque()
-        {
-            self.sprite0_hit = true;
+        if let Some((0, _, _)) = sprite {
+            if bg_color.is_opaque() {
+                self.sprite0_hit = true;
+            }
+        }
+        // Hidden layers and extra sprites only change what's displayed
+        if self.layers.hide_background {
+            bg_color = GLOBAL_BACKGROUND_COLOR;
+        }
+        if self.layers != LayerMask::default() || self.unlimited_sprites {
+            let layers = self.layers;
+            sprite = self.sprite_pixel(self.unlimited_sprites, |oam_index| {
+                !layers.is_sprite_hidden(oam_index)
+            });
         }
         // Determine display color
-        let color = if self.sprite_priorities[i as usize] && sprite_color.is_opaque() {
-            sprite_color
-        } else if bg_color.is_opaque() {
-            bg_color
-        } else if sprite_color.is_opaque() {
-            sprite_color
-        } else {
-            GLOBAL_BACKGROUND_COLOR
+        let color = match sprite {
+            Some((_, true, sprite_color)) => sprite_color,
+            _ if bg_color.is_opaque() => bg_color,
+            Some((_, false, sprite_color)) => sprite_color,
+            None => GLOBAL_BACKGROUND_COLOR,
         };
         // eprintln!("DEBUG - COLOR - {:?}", color);
-        let system_color = self.peek(color.address());
+        let mut system_color = self.peek(color.address());
+        if self.registers.is_greyscale {
+            system_color &= 0x30;
+        }
         self.write_system_pixel(x, y, system_color);
     }
 
     fn background_pixel(&mut self) -> PaletteColor {
-        if !self.is_background_enabled() {
+        let x = self.cycle - 1;
+        if !self.is_background_enabled() || (x < 8 && !self.registers.show_leftmost_background) {
             return GLOBAL_BACKGROUND_COLOR;
         }
         return self.fetch_tile_color_from_shift();
     }
-    fn sprite_pixel(&mut self) -> (u8, PaletteColor) {
-        if !self.is_sprites_enabled() {
-            return (0, GLOBAL_BACKGROUND_COLOR);
-        }
+    // The OAM index, priority and color of the first opaque sprite at this dot, among those whose
+    // OAM index passes `is_included`
+    fn sprite_pixel(
+        &self,
+        include_extra: bool,
+        is_included: impl Fn(u8) -> bool,
+    ) -> Option<(u8, bool, PaletteColor)> {
         let x = self.cycle - 1;
-        for i in 0..self.sprite_count as usize {
-            let spritex = self.sprite_xs[i];
-            let xsub = x as i16 - spritex as i16;
-            if xsub < 0 || xsub > 7 {
+        if !self.is_sprites_enabled() || (x < 8 && !self.registers.show_leftmost_sprite) {
+            return None;
+        }
+        let sprites = (0..self.sprite_count as usize).map(|i| LoadedSprite {
+            index: self.sprite_indices[i],
+            x: self.sprite_xs[i],
+            pattern: self.sprite_patterns[i],
+            palette: self.sprite_palettes[i],
+            is_front: self.sprite_priorities[i],
+        });
+        let extra_sprites = self.extra_sprites.iter().filter(|_| include_extra).cloned();
+        for sprite in sprites.chain(extra_sprites) {
+            let xsub = x as i16 - sprite.x as i16;
+            if !(0..=7).contains(&xsub) || !is_included(sprite.index) {
                 continue;
             }
-            let palette = self.sprite_palettes[i];
-            let color = (self.sprite_patterns[i] >> ((7 - xsub) * 2)) & 0x3;
-            let palette_color = PaletteColor::new_from_parts(palette, color as u8);
+            let color = (sprite.pattern >> ((7 - xsub) * 2)) & 0x3;
+            let palette_color = PaletteColor::new_from_parts(sprite.palette, color as u8);
             if palette_color.is_transparent() {
                 continue;
             }
-            return (i as u8, palette_color);
+            return Some((sprite.index, sprite.is_front, palette_color));
         }
-        return (0, GLOBAL_BACKGROUND_COLOR);
+        None
     }
     fn is_sprites_enabled(&self) -> bool {
         return self.registers.is_sprites_enabled();
@@ -892,33 +1202,136 @@ impl Ppu {
         return self.registers.is_rendering_enabled();
     }
 
-    fn evaluate_sprites(&mut self) {
-        let height = ternary(self.sprite_size, 16, 8);
+    fn clear_secondary_oam(&mut self) {
+        self.oam_bus = 0xFF;
+        if self.cycle.is_multiple_of(2) {
+            self.secondary_oam[(self.cycle / 2 - 1) as usize] = 0xFF;
+        }
+    }
 
-        let mut count = 0;
-        for i in 0..64 {
-            let sprite = self.lookup_sprite(i);
-            let pattern = self.fetch_sprite_pattern(&sprite, self.scanline);
-            match pattern {
-                None => continue,
-                Some(pattern) => {
-                    if count < 8 {
-                        self.sprite_patterns[count] = pattern;
-                        self.sprite_xs[count] = sprite.x;
-                        self.sprite_priorities[count] = sprite.is_front;
-                        self.sprite_indices[count] = i as u8;
-                        self.sprite_palettes[count] = sprite.palette;
+    // https://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+    // Odd cycles read from primary OAM, and even cycles write to secondary OAM.
+    fn evaluate_sprites(&mut self) {
+        if self.cycle == 65 {
+            self.sprite_evaluation = SpriteEvaluation::new();
+        }
+        let mut ev = self.sprite_evaluation;
+        if self.cycle % 2 == 1 {
+            self.oam_bus = self.oam[ev.n as usize * 4 + ev.m as usize];
+            return;
+        }
+        let value = self.oam_bus;
+        match ev.state {
+            SpriteEvaluationState::FindY => {
+                self.secondary_oam[ev.secondary_index as usize] = value;
+                if self.is_sprite_in_range(value) {
+                    self.secondary_oam_indices[ev.secondary_index as usize / 4] = ev.n;
+                    ev.secondary_index += 1;
+                    ev.m = 1;
+                    ev.state = SpriteEvaluationState::CopySprite;
+                } else {
+                    ev.next_sprite();
+                }
+            }
+            SpriteEvaluationState::CopySprite => {
+                self.secondary_oam[ev.secondary_index as usize] = value;
+                ev.secondary_index += 1;
+                ev.m += 1;
+                if ev.m == 4 {
+                    ev.m = 0;
+                    ev.next_sprite();
+                }
+            }
+            SpriteEvaluationState::Overflow => {
+                if self.is_sprite_in_range(value) {
+                    self.sprite_overflow = true;
+                    ev.remaining = 3;
+                    ev.state = SpriteEvaluationState::OverflowCopy;
+                    ev.increment_m_with_carry();
+                } else {
+                    // Hardware bug: m is incremented along with n, so later checks read tile indices,
+                    // attributes, and X coordinates as if they were Y coordinates.
+                    ev.m = (ev.m + 1) & 3;
+                    ev.n += 1;
+                    if ev.n == 64 {
+                        ev.n = 0;
+                        ev.state = SpriteEvaluationState::Done;
                     }
-                    count += 1;
                 }
-            };
+            }
+            SpriteEvaluationState::OverflowCopy => {
+                ev.increment_m_with_carry();
+                ev.remaining -= 1;
+                if ev.remaining == 0 {
+                    ev.state = SpriteEvaluationState::Done;
+                }
+            }
+            SpriteEvaluationState::Done => {
+                // Attempts (and fails) to copy each remaining Y coordinate
+                ev.n = (ev.n + 1) & 63;
+                ev.m = 0;
+            }
         }
+        self.sprite_evaluation = ev;
+    }
 
-        if count > 8 {
-            count = 8;
-            self.sprite_overflow = true;
+    fn is_sprite_in_range(&self, y: u8) -> bool {
+        let height = ternary(self.sprite_size, 16, 8);
+        let row = self.scanline as i16 - y as i16;
+        row >= 0 && row < height
+    }
+
+    // Fetches the pattern of each sprite in secondary OAM, for rendering on the next scanline.
+    fn load_sprites(&mut self) {
+        let count = self.sprite_evaluation.secondary_index as usize / 4;
+        for i in 0..count {
+            let sprite = decode_sprite(
+                self.secondary_oam_indices[i],
+                &self.secondary_oam[i * 4..i * 4 + 4],
+            );
+            self.sprite_patterns[i] = self
+                .fetch_sprite_pattern(&sprite, self.scanline)
+                .unwrap_or(0);
+            self.sprite_xs[i] = sprite.x;
+            self.sprite_priorities[i] = sprite.is_front;
+            self.sprite_indices[i] = sprite.index;
+            self.sprite_palettes[i] = sprite.palette;
         }
         self.sprite_count = count as u8;
+        self.extra_sprites.clear();
+        if self.unlimited_sprites && count == 8 {
+            self.load_extra_sprites();
+        }
+    }
+
+    // The in-range sprites that didn't fit in secondary OAM, in OAM order. Nothing here is
+    // visible to the game.
+    fn load_extra_sprites(&mut self) {
+        for index in 0..64u8 {
+            let i = index as usize * 4;
+            if self.secondary_oam_indices.contains(&index) || !self.is_sprite_in_range(self.oam[i])
+            {
+                continue;
+            }
+            let sprite = decode_sprite(index, &self.oam[i..i + 4]);
+            let pattern = self
+                .fetch_sprite_pattern(&sprite, self.scanline)
+                .unwrap_or(0);
+            self.extra_sprites.push(LoadedSprite {
+                index,
+                x: sprite.x,
+                pattern,
+                palette: sprite.palette,
+                is_front: sprite.is_front,
+            });
+        }
+    }
+
+    fn fetch_secondary_oam(&mut self) {
+        // OAMADDR is reset during sprite tile loading
+        self.oam_ptr = 0;
+        let cycle = (self.cycle - 257) as usize;
+        self.oam_bus = self.secondary_oam[(cycle / 8) * 4 + usize::min(cycle % 8, 3)];
     }
 
     fn fetch_sprite_pattern(&self, sprite: &Sprite, row: u16) -> Option<u16> {
@@ -929,9 +1342,21 @@ impl Ppu {
             return None;
         }
         let row = ternary(sprite.flip_vertical, height - 1 - row, row);
-        let tile = sprite.tile_index; //TODO -- ternary(row >= 8, sprite.tile_index+1, sprite.tile_index);
-        let row = ternary(row >= 8, row - 8, row);
-        let (tile_row0, tile_row1) = self.fetch_pattern_row(PaletteType::Sprite, tile, row as u8);
+        // https://wiki.nesdev.com/w/index.php/PPU_OAM#Byte_1
+        // 8x16 sprites ignore PPUCTRL and select their pattern table with bit 0 of the tile index.
+        // The top half uses the even tile and the bottom half the odd tile following it.
+        let (is_pattern_table_right, tile) = if is_size_16 {
+            let top_tile = sprite.tile_index & 0xFE;
+            (
+                get_bit(sprite.tile_index, 0) > 0,
+                ternary(row >= 8, top_tile + 1, top_tile),
+            )
+        } else {
+            (self.sprite_pattern_table, sprite.tile_index)
+        };
+        let row = row % 8;
+        let (tile_row0, tile_row1) =
+            self.fetch_pattern_row_from_table(is_pattern_table_right, tile, row as u8);
         let (tile_row0, tile_row1) = ternary(
             sprite.flip_horizontal,
             (reverse_bits(tile_row0), reverse_bits(tile_row1)),
@@ -946,14 +1371,22 @@ impl Ppu {
         tile_index: u8,
         ysub: u8,
     ) -> (u16, u16) {
-        // https://wiki.nesdev.com/w/index.php/PPU_pattern_tables
-        let ptr_pattern_table_base = 0x0000;
-        let size_pattern_table = 0x1000;
-        let size_tile = 16;
         let is_pattern_table_right = match palette_type {
             PaletteType::Sprite => self.sprite_pattern_table,
             PaletteType::Background => self.background_pattern_table,
         };
+        self.locate_pattern_row_in_table(is_pattern_table_right, tile_index, ysub)
+    }
+    fn locate_pattern_row_in_table(
+        &self,
+        is_pattern_table_right: bool,
+        tile_index: u8,
+        ysub: u8,
+    ) -> (u16, u16) {
+        // https://wiki.nesdev.com/w/index.php/PPU_pattern_tables
+        let ptr_pattern_table_base = 0x0000;
+        let size_pattern_table = 0x1000;
+        let size_tile = 16;
         let ptr_tile: u16 = ptr_pattern_table_base
             + size_pattern_table * (is_pattern_table_right as u16)
             + (size_tile * tile_index as u16);
@@ -968,6 +1401,16 @@ impl Ppu {
         let tile_row1 = self.peek(ptr_tile_row1);
         return (tile_row0, tile_row1);
     }
+    fn fetch_pattern_row_from_table(
+        &self,
+        is_pattern_table_right: bool,
+        tile_index: u8,
+        ysub: u8,
+    ) -> (u8, u8) {
+        let (ptr_tile_row0, ptr_tile_row1) =
+            self.locate_pattern_row_in_table(is_pattern_table_right, tile_index, ysub);
+        (self.peek(ptr_tile_row0), self.peek(ptr_tile_row1))
+    }
 
     fn fetch_scanline_sprites(&mut self, y: u16) -> Vec<Sprite> {
         let mut vec = Vec::new();
@@ -994,17 +1437,7 @@ impl Ppu {
     }
 
     fn lookup_sprite(&self, i: usize) -> Sprite {
-        let attribute = self.oam[i * 4 + 2];
-        return Sprite {
-            index: i as u8,
-            y: self.oam[i * 4 + 0],
-            tile_index: self.oam[i * 4 + 1],
-            palette: (attribute & 3) + 4,
-            is_front: get_bit(attribute, 5) == 0,
-            flip_horizontal: get_bit(attribute, 6) > 0,
-            flip_vertical: get_bit(attribute, 7) > 0,
-            x: self.oam[i * 4 + 3],
-        };
+        return decode_sprite(i as u8, &self.oam[i * 4..i * 4 + 4]);
     }
 
     fn lookup_global_background_color(&self) -> SystemColor {
@@ -1029,7 +1462,7 @@ impl Ppu {
         self.background_pattern_table = get_bit(v, 4) > 0;
         self.sprite_size = get_bit(v, 5) > 0;
         self.ppu_master_select = get_bit(v, 6) > 0;
-        self.generate_vblank_nmi = get_bit(v, 7) > 0;
+        self.set_generate_vblank_nmi(get_bit(v, 7) > 0);
     }
 
     pub fn write_mask(&mut self, v: u8) {
@@ -1037,6 +1470,10 @@ impl Ppu {
     }
 
     pub fn read_status(&mut self) -> u8 {
+        // Reading one dot before VBlank starts returns it clear, and it never gets set that frame.
+        if self.scanline == self.region.vblank_scanline() && self.cycle == 0 {
+            self.suppress_vblank = true;
+        }
         let ret = (self.open_bus & 0b00011111)
             | ((self.sprite_overflow as u8) << 5)
             | ((self.sprite0_hit as u8) << 6)
@@ -1049,8 +1486,18 @@ impl Ppu {
         self.oam_ptr = v;
     }
     pub fn read_oam_data(&mut self) -> u8 {
+        // During rendering, reads see whatever sprite evaluation is reading or writing.
+        if self.is_rendering_enabled()
+            && self.scanline < 240
+            && self.cycle >= 1
+            && self.cycle <= 320
+        {
+            return self.oam_bus;
+        }
         let ptr: u8 = self.oam_ptr;
-        return self.oam[ptr as usize];
+        let value = self.oam[ptr as usize];
+        // Bits 2-4 of the attribute byte aren't implemented, and read back as 0.
+        return ternary(ptr % 4 == 2, value & 0xE3, value);
     }
     pub fn write_oam_data(&mut self, v: u8) {
         let ptr: u8 = self.oam_ptr;
@@ -1065,24 +1512,28 @@ impl Ppu {
     }
     pub fn read_data(&mut self) -> u8 {
         let ptr = self.registers.vram_ptr();
-        self.registers.advance_vram_ptr();
+        let prerender_scanline = self.region.prerender_scanline();
+        self.registers.advance_vram_ptr(prerender_scanline);
         let val = self.peek(ptr);
         if ptr < 0x3f00 {
             let old_val = self.ppudata_buffer;
             self.ppudata_buffer = val;
             old_val
+        } else if self.registers.is_greyscale {
+            val & 0x30
         } else {
             val
         }
     }
     pub fn write_data(&mut self, v: u8) {
         let ptr = self.registers.vram_ptr();
-        self.registers.advance_vram_ptr();
+        let prerender_scanline = self.region.prerender_scanline();
+        self.registers.advance_vram_ptr(prerender_scanline);
         // eprintln!("DEBUG - PPU WRITE DATA - {:x} {:x} {:x}", ptr, v, self.registers.vram_ptr());
         self.poke(ptr, v);
     }
-    fn lookup_system_pixel(&self, i: SystemColor) -> RgbColor {
-        return SYSTEM_PALETTE[i as usize];
+    fn lookup_system_pixel(&self, i: SystemColor, emphasis: u8) -> RgbColor {
+        return self.palette[((emphasis as usize) << 6) | (i as usize & 0x3F)];
     }
     fn write_system_pixel(&mut self, x: u16, y: u16, c: SystemColor) {
         if x >= 256 || y >= 240 {
@@ -1090,19 +1541,60 @@ impl Ppu {
         }
         let i = (x + 256 * y) as usize;
         self.display[i] = c;
+        self.display_emphasis[i] = self.registers.emphasis();
     }
     fn set_vblank(&mut self, new_vblank: bool) {
         let vblank = self.nmi_occurred;
         if vblank != new_vblank {
             //eprintln!("DEBUG - VBLANK CHANGED FROM {:?} TO {:?}", vblank, new_vblank);
         }
-        self.nmi_occurred = new_vblank;
-        self.clocks_until_nmi = 15;
+        self.set_nmi_line(new_vblank, self.generate_vblank_nmi);
+    }
+    fn set_generate_vblank_nmi(&mut self, generate_vblank_nmi: bool) {
+        self.set_nmi_line(self.nmi_occurred, generate_vblank_nmi);
+    }
+    // The NMI line is the VBlank flag ANDed with PPUCTRL bit 7, and the CPU triggers on its rising edge.
+    // So enabling NMI during VBlank fires one immediately, and clearing either input before the CPU
+    // has taken a pending NMI(e.g. a $2002 read right as VBlank starts) cancels it.
+    fn set_nmi_line(&mut self, nmi_occurred: bool, generate_vblank_nmi: bool) {
+        let was_asserted = self.nmi_occurred && self.generate_vblank_nmi;
+        self.nmi_occurred = nmi_occurred;
+        self.generate_vblank_nmi = generate_vblank_nmi;
+        let is_asserted = nmi_occurred && generate_vblank_nmi;
+        if is_asserted && !was_asserted {
+            self.is_vblank_nmi = true;
+        } else if !is_asserted {
+            self.is_vblank_nmi = false;
+        }
     }
 }
 
-type RgbColor = (u8, u8, u8);
-type SystemPalette = [RgbColor; 64];
+pub type RgbColor = (u8, u8, u8);
+pub type SystemPalette = [RgbColor; 64];
+// SYSTEM_PALETTE repeated for each of the 8 combinations of PPUMASK emphasis bits, indexed by emphasis << 6 | color.
+pub type ExtendedPalette = [RgbColor; 512];
+
+// https://wiki.nesdev.com/w/index.php/Colour_emphasis
+// Each emphasis bit darkens the two other color channels.
+const EMPHASIS_ATTENUATION: f64 = 0.816328;
+
+pub fn extend_palette(palette: &SystemPalette) -> ExtendedPalette {
+    let mut ret = [(0, 0, 0); 512];
+    for emphasis in 0..8 {
+        let attenuate = |bit: u8| ternary(emphasis & bit > 0, EMPHASIS_ATTENUATION, 1.0);
+        let red_scale = attenuate(0b010) * attenuate(0b100);
+        let green_scale = attenuate(0b001) * attenuate(0b100);
+        let blue_scale = attenuate(0b001) * attenuate(0b010);
+        for (color, (r, g, b)) in palette.iter().enumerate() {
+            ret[((emphasis as usize) << 6) | color] = (
+                (*r as f64 * red_scale) as u8,
+                (*g as f64 * green_scale) as u8,
+                (*b as f64 * blue_scale) as u8,
+            );
+        }
+    }
+    ret
+}
 
 // The NES can refer to 64 separate colors. This table has RGB values for each.
 pub const SYSTEM_PALETTE: SystemPalette = [
@@ -1192,4 +1684,260 @@ mod tests {
         let o = 0b01111001;
         assert_eq!(reverse_bits(a), o);
     }
+    #[test]
+    fn test_extend_palette() {
+        let palette = extend_palette(&SYSTEM_PALETTE);
+        assert_eq!(palette[0x30], SYSTEM_PALETTE[0x30]);
+        // Red emphasis keeps red and darkens green and blue
+        assert_eq!(palette[(1 << 6) | 0x30], (252, 205, 205));
+        // All three bits darken every channel
+        assert_eq!(palette[(7 << 6) | 0x30], (167, 167, 167));
+    }
+    // Runs sprite evaluation for `scanline`, with `sprites` placed at the start of OAM.
+    fn evaluate_scanline(sprites: &[[u8; 4]], scanline: u16) -> Ppu {
+        use crate::mapper::Ram;
+        let mut ppu = Ppu::new();
+        ppu.mapper = Box::new(Ram::new(0x4000));
+        ppu.oam = [0xFF; 256];
+        for (i, sprite) in sprites.iter().enumerate() {
+            ppu.oam[i * 4..i * 4 + 4].copy_from_slice(sprite);
+        }
+        ppu.write_mask(0b00011000);
+        ppu.scanline = scanline;
+        while ppu.cycle != 257 {
+            ppu.clock();
+        }
+        ppu
+    }
+    #[test]
+    fn test_sprite_evaluation() {
+        let ppu = evaluate_scanline(&[[0xFF; 4], [5, 1, 0, 20], [7, 2, 0, 30]], 10);
+        assert_eq!(ppu.sprite_count, 2);
+        assert_eq!(ppu.sprite_indices[0..2], [1, 2]);
+        assert_eq!(ppu.sprite_xs[0..2], [20, 30]);
+        assert_eq!(ppu.sprite_overflow, false);
+    }
+    #[test]
+    fn test_sprite_overflow() {
+        let ppu = evaluate_scanline(&[[5, 0, 0, 0]; 9], 10);
+        assert_eq!(ppu.sprite_count, 8);
+        assert_eq!(ppu.sprite_overflow, true);
+    }
+    #[test]
+    fn test_sprite_overflow_bug() {
+        let mut sprites = [[5, 0, 0, 0]; 10];
+        // The 9th sprite is out of range, so the next check reads the 10th sprite's tile index as its Y.
+        sprites[8] = [0xFF; 4];
+        sprites[9] = [0xFF, 5, 0xFF, 0xFF];
+        let ppu = evaluate_scanline(&sprites, 10);
+        assert_eq!(ppu.sprite_count, 8);
+        assert_eq!(ppu.sprite_overflow, true);
+        // The 10th sprite is in range, but the check reads its tile index.
+        sprites[9] = [5, 0xFF, 0xFF, 0xFF];
+        let ppu = evaluate_scanline(&sprites, 10);
+        assert_eq!(ppu.sprite_overflow, false);
+    }
+    #[test]
+    fn test_sprite_8x16() {
+        use crate::mapper::Ram;
+        let mut ppu = Ppu::new();
+        ppu.mapper = Box::new(Ram::new(0x2000));
+        ppu.poke(0x1020, 0xFF); // Tile $02 in the right pattern table, row 0
+        ppu.poke(0x1030, 0x0F); // Tile $03, row 0
+        ppu.write_control(0b00100000); // 8x16 sprites, PPUCTRL sprite table = left
+        let mut sprite = Sprite {
+            index: 0,
+            x: 0,
+            y: 10,
+            tile_index: 0x03,
+            palette: 4,
+            is_front: true,
+            flip_horizontal: false,
+            flip_vertical: false,
+        };
+        assert_eq!(ppu.fetch_sprite_pattern(&sprite, 10), Some(0x5555));
+        assert_eq!(ppu.fetch_sprite_pattern(&sprite, 18), Some(0x0055));
+        assert_eq!(ppu.fetch_sprite_pattern(&sprite, 26), None);
+        sprite.flip_vertical = true;
+        assert_eq!(ppu.fetch_sprite_pattern(&sprite, 25), Some(0x5555));
+        assert_eq!(ppu.fetch_sprite_pattern(&sprite, 17), Some(0x0055));
+    }
+    fn clock_until(ppu: &mut Ppu, scanline: u16, cycle: u16) {
+        while ppu.scanline != scanline || ppu.cycle != cycle {
+            ppu.clock();
+        }
+    }
+    fn test_ppu() -> Ppu {
+        use crate::mapper::Ram;
+        let mut ppu = Ppu::new();
+        ppu.mapper = Box::new(Ram::new(0x4000));
+        ppu
+    }
+    #[test]
+    fn test_layer_mask() {
+        let mut ppu = test_ppu();
+        ppu.write_mask(0b00011110);
+        ppu.poke(0x3F00, 0x0F);
+        ppu.poke(0x3F11, 0x16);
+        ppu.poke(0x3F15, 0x12);
+        // OAM entries 3 and 7 overlap at x=0, with entry 3 in front
+        ppu.sprite_count = 2;
+        ppu.sprite_xs[0..2].copy_from_slice(&[0, 0]);
+        ppu.sprite_patterns[0..2].copy_from_slice(&[0x5555, 0x5555]);
+        ppu.sprite_palettes[0..2].copy_from_slice(&[4, 5]);
+        ppu.sprite_indices[0..2].copy_from_slice(&[3, 7]);
+        ppu.sprite_priorities[0..2].copy_from_slice(&[true, true]);
+        ppu.cycle = 1;
+        let render = |ppu: &mut Ppu, layers: LayerMask| {
+            ppu.set_layers(layers);
+            ppu.render_pixel();
+            ppu.display[0]
+        };
+        assert_eq!(render(&mut ppu, LayerMask::default()), 0x16);
+        let mut layers = LayerMask::default();
+        layers.toggle_sprite(3);
+        assert_eq!(render(&mut ppu, layers), 0x12);
+        layers.hide_sprites = true;
+        assert_eq!(render(&mut ppu, layers), 0x0F);
+    }
+    #[test]
+    fn test_unlimited_sprites() {
+        use crate::mapper::Ram;
+        let mut ppu = Ppu::new();
+        ppu.mapper = Box::new(Ram::new(0x4000));
+        ppu.poke(0x3F00, 0x0F);
+        ppu.poke(0x3F11, 0x16);
+        // Tile 0 is solid color 1, and 10 sprites share scanline 10 side by side
+        for row in 0..8 {
+            ppu.poke(row, 0xFF);
+        }
+        ppu.oam = [0xFF; 256];
+        for i in 0..10 {
+            ppu.oam[i * 4..i * 4 + 4].copy_from_slice(&[5, 0, 0, i as u8 * 8]);
+        }
+        ppu.set_unlimited_sprites(true);
+        ppu.write_mask(0b00010100);
+        ppu.scanline = 10;
+        while ppu.cycle != 258 {
+            ppu.clock();
+        }
+        // The game still sees the hardware limit
+        assert_eq!(ppu.sprite_count, 8);
+        assert_eq!(ppu.sprite_overflow, true);
+        assert_eq!(ppu.extra_sprites.len(), 2);
+        let render = |ppu: &mut Ppu, x: u16| {
+            ppu.cycle = x + 1;
+            ppu.render_pixel();
+            ppu.display[ppu.scanline as usize * RENDER_WIDTH + x as usize]
+        };
+        assert_eq!(render(&mut ppu, 9 * 8 + 3), 0x16);
+        assert_eq!(render(&mut ppu, 10 * 8 + 3), 0x0F);
+        ppu.set_unlimited_sprites(false);
+        assert_eq!(render(&mut ppu, 9 * 8 + 3), 0x0F);
+    }
+    #[test]
+    fn test_odd_frame_skipped_dot() {
+        let mut ppu = test_ppu();
+        ppu.write_mask(0b00011000);
+        let mut dots = vec![];
+        for _ in 0..3 {
+            let frame = ppu.frame;
+            let mut num_dots = 0;
+            while ppu.frame == frame {
+                ppu.clock();
+                num_dots += 1;
+            }
+            dots.push(num_dots);
+        }
+        assert_eq!(dots, vec![341 * 262, 341 * 262 - 1, 341 * 262]);
+        // Without rendering every frame is full length.
+        ppu.write_mask(0);
+        let frame = ppu.frame;
+        let mut num_dots = 0;
+        while ppu.frame == frame {
+            ppu.clock();
+            num_dots += 1;
+        }
+        assert_eq!(num_dots, 341 * 262);
+    }
+    #[test]
+    fn test_region_frame_timing() {
+        for &region in &[Region::Pal, Region::Dendy] {
+            let mut ppu = test_ppu();
+            ppu.set_region(region);
+            ppu.write_control(0x80);
+            ppu.write_mask(0b00011000);
+            for _ in 0..2 {
+                let frame = ppu.frame;
+                let mut num_dots = 0;
+                let mut nmi_scanline = None;
+                while ppu.frame == frame {
+                    ppu.clock();
+                    num_dots += 1;
+                    if ppu.is_vblank_nmi {
+                        nmi_scanline = Some(ppu.scanline);
+                        ppu.is_vblank_nmi = false;
+                    }
+                }
+                assert_eq!(num_dots, 341 * 312);
+                assert_eq!(nmi_scanline, Some(region.vblank_scanline()));
+            }
+        }
+    }
+    #[test]
+    fn test_vblank_nmi() {
+        let mut ppu = test_ppu();
+        let vblank = ppu.region.vblank_scanline();
+        ppu.write_control(0x80);
+        clock_until(&mut ppu, vblank, 0);
+        assert_eq!(ppu.is_vblank_nmi, false);
+        ppu.clock();
+        assert_eq!(ppu.is_vblank_nmi, true);
+        ppu.is_vblank_nmi = false;
+        // Toggling NMI on during VBlank fires another one immediately.
+        ppu.write_control(0x00);
+        ppu.write_control(0x80);
+        assert_eq!(ppu.is_vblank_nmi, true);
+    }
+    #[test]
+    fn test_vblank_read_race() {
+        let mut ppu = test_ppu();
+        let vblank = ppu.region.vblank_scanline();
+        ppu.write_control(0x80);
+        // Reading the dot before VBlank suppresses both the flag and the NMI for the frame.
+        clock_until(&mut ppu, vblank, 0);
+        assert_eq!(ppu.read_status() & 0x80, 0);
+        ppu.clock();
+        assert_eq!(ppu.is_vblank_nmi, false);
+        assert_eq!(ppu.read_status() & 0x80, 0);
+        // Reading as VBlank starts sees the flag, but cancels the pending NMI.
+        ppu.clock();
+        clock_until(&mut ppu, vblank, 1);
+        assert_eq!(ppu.is_vblank_nmi, true);
+        assert_eq!(ppu.read_status() & 0x80, 0x80);
+        assert_eq!(ppu.is_vblank_nmi, false);
+        // Toggling NMI on after the flag was cleared doesn't fire one.
+        ppu.write_control(0x00);
+        ppu.write_control(0x80);
+        assert_eq!(ppu.is_vblank_nmi, false);
+    }
+    #[test]
+    fn test_render_into() {
+        let mut ppu = test_ppu();
+        ppu.display[RENDER_WIDTH + 1] = 0x16;
+        let (r, g, b) = SYSTEM_PALETTE[0x16];
+        let stride = RENDER_WIDTH * 4 + 16;
+        let mut buf = vec![0; stride * RENDER_HEIGHT];
+        ppu.render_into(&mut buf, PixelFormat::Bgra8888, Some(stride));
+        assert_eq!(buf[stride + 4..stride + 8], [b, g, r, 0xFF]);
+        assert_eq!(buf[RENDER_WIDTH * 4..stride], [0; 16]);
+
+        let mut buf = vec![0; UNRENDER_SIZE];
+        ppu.render_into(&mut buf, PixelFormat::PaletteIndex, None);
+        assert_eq!(buf[..], ppu.display[..]);
+        assert_eq!(
+            ppu.render()[(RENDER_WIDTH + 1) * 3..(RENDER_WIDTH + 2) * 3],
+            [r, g, b]
+        );
+    }
 }
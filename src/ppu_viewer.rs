@@ -0,0 +1,236 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use crate::common::get_bit;
+use crate::mapper::AddressSpace;
+use crate::ppu::{Ppu, RgbColor, RENDER_HEIGHT, RENDER_WIDTH};
+
+// Debug views of PPU memory, rendered as RGB24 images. They only peek at memory, so they can
+// be drawn at any time without disturbing the emulation.
+
+// All four nametables, laid out as they're addressed: $2000 $2400 / $2800 $2C00
+pub const NAMETABLES_WIDTH: usize = RENDER_WIDTH * 2;
+pub const NAMETABLES_HEIGHT: usize = RENDER_HEIGHT * 2;
+// Both pattern tables side by side, as 16x16 grids of tiles
+pub const PATTERN_TABLES_WIDTH: usize = 256;
+pub const PATTERN_TABLES_HEIGHT: usize = 128;
+// The 64 sprites in an 8x8 grid, with room for 8x16 sprites
+pub const SPRITES_WIDTH: usize = 64;
+pub const SPRITES_HEIGHT: usize = 128;
+// The 32 palette entries: background palettes on top, sprite palettes below
+pub const PALETTES_WIDTH: usize = 256;
+pub const PALETTES_HEIGHT: usize = 32;
+
+const ADDRESS_NAMETABLE0: u16 = 0x2000;
+const ADDRESS_PALETTE0: u16 = 0x3F00;
+const VIEWPORT_COLOR: RgbColor = (255, 0, 255);
+const PALETTE_SWATCH_SIZE: usize = 16;
+
+// Draws the background as the PPU would with the current pattern table, and outlines the part
+// the next frame will show.
+pub fn render_nametables(ppu: &Ppu) -> Vec<u8> {
+    let mut buf = vec![0; NAMETABLES_WIDTH * NAMETABLES_HEIGHT * 3];
+    let pattern_table = ppu.background_pattern_table_address();
+    for y in 0..NAMETABLES_HEIGHT {
+        for x in 0..NAMETABLES_WIDTH {
+            let nametable = (y / RENDER_HEIGHT) * 2 + x / RENDER_WIDTH;
+            let base = ADDRESS_NAMETABLE0 + 0x400 * nametable as u16;
+            let (tile_x, tile_y) = ((x % RENDER_WIDTH) / 8, (y % RENDER_HEIGHT) / 8);
+            let tile = ppu.peek(base + (tile_y * 32 + tile_x) as u16);
+            let attribute = ppu.peek(base + 0x3C0 + ((tile_y / 4) * 8 + tile_x / 4) as u16);
+            let shift = ((tile_y % 4) / 2) * 4 + ((tile_x % 4) / 2) * 2;
+            let palette = (attribute >> shift) & 0x3;
+            let pixel = pattern_pixel(ppu, pattern_table, tile, x % 8, y % 8);
+            let color = palette_color(ppu, palette, pixel);
+            write_pixel(&mut buf, NAMETABLES_WIDTH, x, y, color);
+        }
+    }
+    // The viewport wraps around the edges of the nametables
+    let (scroll_x, scroll_y) = ppu.scroll();
+    let (scroll_x, scroll_y) = (scroll_x as usize, scroll_y as usize);
+    for i in 0..RENDER_WIDTH {
+        let x = (scroll_x + i) % NAMETABLES_WIDTH;
+        write_pixel(
+            &mut buf,
+            NAMETABLES_WIDTH,
+            x,
+            scroll_y % NAMETABLES_HEIGHT,
+            VIEWPORT_COLOR,
+        );
+        let bottom = (scroll_y + RENDER_HEIGHT - 1) % NAMETABLES_HEIGHT;
+        write_pixel(&mut buf, NAMETABLES_WIDTH, x, bottom, VIEWPORT_COLOR);
+    }
+    for i in 0..RENDER_HEIGHT {
+        let y = (scroll_y + i) % NAMETABLES_HEIGHT;
+        write_pixel(
+            &mut buf,
+            NAMETABLES_WIDTH,
+            scroll_x % NAMETABLES_WIDTH,
+            y,
+            VIEWPORT_COLOR,
+        );
+        let right = (scroll_x + RENDER_WIDTH - 1) % NAMETABLES_WIDTH;
+        write_pixel(&mut buf, NAMETABLES_WIDTH, right, y, VIEWPORT_COLOR);
+    }
+    buf
+}
+
+// `palette` is 0-3 for the background palettes, or 4-7 for the sprite palettes.
+pub fn render_pattern_tables(ppu: &Ppu, palette: u8) -> Vec<u8> {
+    let mut buf = vec![0; PATTERN_TABLES_WIDTH * PATTERN_TABLES_HEIGHT * 3];
+    for y in 0..PATTERN_TABLES_HEIGHT {
+        for x in 0..PATTERN_TABLES_WIDTH {
+            let pattern_table = ((x / 128) * 0x1000) as u16;
+            let tile = ((y / 8) * 16 + (x % 128) / 8) as u8;
+            let pixel = pattern_pixel(ppu, pattern_table, tile, x % 8, y % 8);
+            let color = palette_color(ppu, palette & 0x7, pixel);
+            write_pixel(&mut buf, PATTERN_TABLES_WIDTH, x, y, color);
+        }
+    }
+    buf
+}
+
+// Each sprite is drawn unflipped in its own 8x16 cell, whatever its position on screen.
+pub fn render_sprites(ppu: &Ppu) -> Vec<u8> {
+    let mut buf = vec![0; SPRITES_WIDTH * SPRITES_HEIGHT * 3];
+    let is_tall = ppu.is_tall_sprites();
+    for sprite in 0..64 {
+        let tile_index = ppu.oam[sprite * 4 + 1];
+        let palette = 4 + (ppu.oam[sprite * 4 + 2] & 0x3);
+        let (cell_x, cell_y) = ((sprite % 8) * 8, (sprite / 8) * 16);
+        for y in 0..16 {
+            for x in 0..8 {
+                let color = if !is_tall && y >= 8 {
+                    palette_color(ppu, 0, 0)
+                } else {
+                    let (pattern_table, tile) = if is_tall {
+                        let table = ((tile_index & 1) as u16) * 0x1000;
+                        (table, (tile_index & 0xFE) + (y / 8) as u8)
+                    } else {
+                        (ppu.sprite_pattern_table_address(), tile_index)
+                    };
+                    let pixel = pattern_pixel(ppu, pattern_table, tile, x, y % 8);
+                    palette_color(ppu, palette, pixel)
+                };
+                write_pixel(&mut buf, SPRITES_WIDTH, cell_x + x, cell_y + y, color);
+            }
+        }
+    }
+    buf
+}
+
+pub fn render_palettes(ppu: &Ppu) -> Vec<u8> {
+    let mut buf = vec![0; PALETTES_WIDTH * PALETTES_HEIGHT * 3];
+    for y in 0..PALETTES_HEIGHT {
+        for x in 0..PALETTES_WIDTH {
+            let entry = (y / PALETTE_SWATCH_SIZE) * 16 + x / PALETTE_SWATCH_SIZE;
+            let color = ppu.peek(ADDRESS_PALETTE0 + entry as u16);
+            write_pixel(&mut buf, PALETTES_WIDTH, x, y, system_color(ppu, color));
+        }
+    }
+    buf
+}
+
+// The 2-bit color of one pixel of a tile
+fn pattern_pixel(ppu: &Ppu, pattern_table: u16, tile: u8, x: usize, y: usize) -> u8 {
+    let address = pattern_table + (tile as u16) * 16 + y as u16;
+    let low = ppu.peek(address);
+    let high = ppu.peek(address + 8);
+    let bit = 7 - x as u8;
+    get_bit(high, bit) << 1 | get_bit(low, bit)
+}
+
+// Color 0 of every palette shows the universal background color, as it does on screen.
+fn palette_color(ppu: &Ppu, palette: u8, pixel: u8) -> RgbColor {
+    let address = if pixel == 0 {
+        ADDRESS_PALETTE0
+    } else {
+        ADDRESS_PALETTE0 + (palette as u16) * 4 + pixel as u16
+    };
+    system_color(ppu, ppu.peek(address))
+}
+
+fn system_color(ppu: &Ppu, color: u8) -> RgbColor {
+    ppu.palette()[(color & 0x3F) as usize]
+}
+
+fn write_pixel(buf: &mut [u8], width: usize, x: usize, y: usize, (r, g, b): RgbColor) {
+    let i = (y * width + x) * 3;
+    buf[i..i + 3].copy_from_slice(&[r, g, b]);
+}
+
+mod tests {
+    use super::*;
+    use crate::mapper::Ram;
+
+    fn test_ppu() -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.mapper = Box::new(Ram::new(0x4000));
+        // Color 1 of background palette 1 is red, and the backdrop is black
+        ppu.poke(0x3F00, 0x0F);
+        ppu.poke(0x3F05, 0x16);
+        ppu.poke(0x3F11, 0x12);
+        // Tile 1 is solid color 1
+        for row in 0..8 {
+            ppu.poke(0x0010 + row, 0xFF);
+        }
+        ppu
+    }
+    fn pixel_at(buf: &[u8], width: usize, x: usize, y: usize) -> RgbColor {
+        let i = (y * width + x) * 3;
+        (buf[i], buf[i + 1], buf[i + 2])
+    }
+
+    #[test]
+    fn test_render_nametables() {
+        let mut ppu = test_ppu();
+        let red = ppu.palette()[0x16];
+        let black = ppu.palette()[0x0F];
+        // Tile (2, 3) of the $2400 nametable uses tile 1, and palette 1 from the bottom-right
+        // quadrant of its attribute byte
+        ppu.poke(0x2400 + 3 * 32 + 2, 1);
+        ppu.poke(0x2400 + 0x3C0, 0b01 << 6);
+        let buf = render_nametables(&ppu);
+        assert_eq!(
+            pixel_at(&buf, NAMETABLES_WIDTH, 256 + 2 * 8 + 3, 3 * 8 + 1),
+            red
+        );
+        assert_eq!(
+            pixel_at(&buf, NAMETABLES_WIDTH, 2 * 8 + 3, 3 * 8 + 1),
+            black
+        );
+        // The viewport starts at the top-left with no scroll
+        assert_eq!(pixel_at(&buf, NAMETABLES_WIDTH, 100, 0), VIEWPORT_COLOR);
+        assert_eq!(pixel_at(&buf, NAMETABLES_WIDTH, 255, 100), VIEWPORT_COLOR);
+        assert_eq!(pixel_at(&buf, NAMETABLES_WIDTH, 256, 100), black);
+    }
+    #[test]
+    fn test_render_pattern_tables() {
+        let ppu = test_ppu();
+        let buf = render_pattern_tables(&ppu, 1);
+        assert_eq!(
+            pixel_at(&buf, PATTERN_TABLES_WIDTH, 8, 0),
+            ppu.palette()[0x16]
+        );
+        assert_eq!(
+            pixel_at(&buf, PATTERN_TABLES_WIDTH, 0, 0),
+            ppu.palette()[0x0F]
+        );
+    }
+    #[test]
+    fn test_render_sprites_and_palettes() {
+        let mut ppu = test_ppu();
+        // Sprite 9 uses tile 1 and sprite palette 0
+        ppu.oam[9 * 4 + 1] = 1;
+        let buf = render_sprites(&ppu);
+        assert_eq!(pixel_at(&buf, SPRITES_WIDTH, 8, 16), ppu.palette()[0x12]);
+        assert_eq!(pixel_at(&buf, SPRITES_WIDTH, 0, 0), ppu.palette()[0x0F]);
+
+        let buf = render_palettes(&ppu);
+        assert_eq!(
+            pixel_at(&buf, PALETTES_WIDTH, 5 * 16, 0),
+            ppu.palette()[0x16]
+        );
+        assert_eq!(pixel_at(&buf, PALETTES_WIDTH, 16, 16), ppu.palette()[0x12]);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::serialization::Savable;
+
+use std::io::Read;
+use std::io::Write;
+use std::str::FromStr;
+
+// The consoles sold in each region run the same chips off different clocks and video timings.
+// https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+pub const DOTS_PER_SCANLINE: u16 = 341;
+
+impl Region {
+    // Hz
+    pub fn master_clock_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 236_250_000.0 / 11.0,
+            Region::Pal | Region::Dendy => 26_601_712.5,
+        }
+    }
+    pub fn master_clocks_per_cpu_clock(&self) -> u64 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+    pub fn master_clocks_per_ppu_clock(&self) -> u64 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+    pub fn cpu_clock_rate(&self) -> f64 {
+        self.master_clock_rate() / self.master_clocks_per_cpu_clock() as f64
+    }
+    // Including the pre-render scanline, which is always the last one.
+    pub fn scanlines_per_frame(&self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+    // The scanline that sets the VBlank flag. Dendy keeps NTSC's 20 lines of VBlank,
+    // and pads the extra lines out after the post-render scanline instead.
+    pub fn vblank_scanline(&self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+    pub fn prerender_scanline(&self) -> u16 {
+        self.scanlines_per_frame() - 1
+    }
+    // Only the NTSC PPU skips the last dot of the pre-render line on odd frames.
+    pub fn has_skipped_dot(&self) -> bool {
+        *self == Region::Ntsc
+    }
+    pub fn dots_per_frame(&self) -> f64 {
+        let dots = (DOTS_PER_SCANLINE as u32 * self.scanlines_per_frame() as u32) as f64;
+        if self.has_skipped_dot() {
+            dots - 0.5
+        } else {
+            dots
+        }
+    }
+    pub fn cpu_clocks_per_frame(&self) -> f64 {
+        self.dots_per_frame() * self.master_clocks_per_ppu_clock() as f64
+            / self.master_clocks_per_cpu_clock() as f64
+    }
+    // Exact frames per second, as a numerator and denominator
+    pub fn frame_rate(&self) -> (u64, u64) {
+        let (clock_numerator, clock_denominator) = match self {
+            Region::Ntsc => (236_250_000, 11),
+            Region::Pal | Region::Dendy => (53_203_425, 2),
+        };
+        let half_dots = (self.dots_per_frame() * 2.0) as u64;
+        let numerator = clock_numerator * 2;
+        let denominator = clock_denominator * half_dots * self.master_clocks_per_ppu_clock();
+        let divisor = gcd(numerator, denominator);
+        (numerator / divisor, denominator / divisor)
+    }
+    pub fn frames_per_second(&self) -> f64 {
+        self.master_clock_rate()
+            / (self.dots_per_frame() * self.master_clocks_per_ppu_clock() as f64)
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Region, String> {
+        match s.to_lowercase().as_str() {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!(
+                "Unknown region {:?}. Expected ntsc, pal or dendy",
+                s
+            )),
+        }
+    }
+}
+
+impl Savable for Region {
+    fn save(&self, fh: &mut dyn Write) {
+        let x: u8 = match self {
+            Region::Ntsc => 0,
+            Region::Pal => 1,
+            Region::Dendy => 2,
+        };
+        x.save(fh);
+    }
+    fn load(&mut self, fh: &mut dyn Read) {
+        let mut x: u8 = 0;
+        x.load(fh);
+        *self = match x {
+            0 => Region::Ntsc,
+            1 => Region::Pal,
+            2 => Region::Dendy,
+            _ => panic!("Unexpected region {}", x),
+        };
+    }
+}
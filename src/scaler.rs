@@ -0,0 +1,506 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use crate::serialization::Savable;
+
+use std::io::Read;
+use std::io::Write;
+use std::str::FromStr;
+
+// Pixel-art upscalers that work on RGB24 frames, such as the output of Ppu::render_into or
+// NtscFilter::render_into.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Scaler {
+    #[default]
+    None,
+    Scale2x,
+    Scale3x,
+    Hq2x,
+    Xbr2x,
+}
+
+impl Scaler {
+    pub fn factor(&self) -> usize {
+        match self {
+            Scaler::None => 1,
+            Scaler::Scale2x | Scaler::Hq2x | Scaler::Xbr2x => 2,
+            Scaler::Scale3x => 3,
+        }
+    }
+}
+
+impl FromStr for Scaler {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Scaler, String> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Scaler::None),
+            "scale2x" => Ok(Scaler::Scale2x),
+            "scale3x" => Ok(Scaler::Scale3x),
+            "hq2x" => Ok(Scaler::Hq2x),
+            "xbr" | "xbr2x" => Ok(Scaler::Xbr2x),
+            _ => Err(format!(
+                "Unknown scaler {:?}. Expected none, scale2x, scale3x, hq2x or xbr",
+                s
+            )),
+        }
+    }
+}
+
+impl Savable for Scaler {
+    fn save(&self, fh: &mut dyn Write) {
+        let x: u8 = match self {
+            Scaler::None => 0,
+            Scaler::Scale2x => 1,
+            Scaler::Scale3x => 2,
+            Scaler::Hq2x => 3,
+            Scaler::Xbr2x => 5,
+        };
+        x.save(fh);
+    }
+    fn load(&mut self, fh: &mut dyn Read) {
+        let mut x: u8 = 0;
+        x.load(fh);
+        *self = match x {
+            0 => Scaler::None,
+            1 => Scaler::Scale2x,
+            2 => Scaler::Scale3x,
+            3 => Scaler::Hq2x,
+            5 => Scaler::Xbr2x,
+            _ => panic!("Unexpected scaler {}", x),
+        };
+    }
+}
+
+type Rgb = [u8; 3];
+
+// The source frame, with reads past the edges clamped to the nearest pixel
+struct Frame<'a> {
+    pixels: &'a [u8],
+    width: usize,
+    height: usize,
+}
+
+impl<'a> Frame<'a> {
+    fn get(&self, x: usize, y: usize, dx: isize, dy: isize) -> Rgb {
+        let x = (x as isize + dx).clamp(0, self.width as isize - 1) as usize;
+        let y = (y as isize + dy).clamp(0, self.height as isize - 1) as usize;
+        let i = (y * self.width + x) * 3;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2]]
+    }
+}
+
+// Scales a `width` x `height` RGB24 frame into `dst`, whose rows start `stride` bytes apart.
+pub fn scale_frame(
+    scaler: Scaler,
+    src: &[u8],
+    width: usize,
+    height: usize,
+    dst: &mut [u8],
+    stride: Option<usize>,
+) {
+    assert!(src.len() >= width * height * 3, "Source frame is too small");
+    let factor = scaler.factor();
+    let row_size = width * factor * 3;
+    let stride = stride.unwrap_or(row_size);
+    assert!(
+        stride >= row_size,
+        "Stride {} is smaller than a row",
+        stride
+    );
+    assert!(
+        dst.len() >= stride * (height * factor - 1) + row_size,
+        "Buffer of {} bytes is too small for a {}x frame with stride {}",
+        dst.len(),
+        factor,
+        stride
+    );
+    let frame = Frame {
+        pixels: src,
+        width,
+        height,
+    };
+    let mut block = [[0u8; 3]; 9];
+    for y in 0..height {
+        for x in 0..width {
+            match scaler {
+                Scaler::None => block[0] = frame.get(x, y, 0, 0),
+                Scaler::Scale2x => scale2x(&frame, x, y, &mut block),
+                Scaler::Scale3x => scale3x(&frame, x, y, &mut block),
+                Scaler::Hq2x => hq2x(&frame, x, y, &mut block),
+                Scaler::Xbr2x => xbr2x(&frame, x, y, &mut block),
+            }
+            for sy in 0..factor {
+                let row = (y * factor + sy) * stride;
+                for sx in 0..factor {
+                    let i = row + (x * factor + sx) * 3;
+                    dst[i..i + 3].copy_from_slice(&block[sy * factor + sx]);
+                }
+            }
+        }
+    }
+}
+
+// Darkens the last row of each `factor`-tall block of output rows, like the gaps between a CRT's
+// scanlines. With no scaling, every other row is darkened instead.
+pub fn apply_scanlines(
+    buf: &mut [u8],
+    width: usize,
+    height: usize,
+    stride: Option<usize>,
+    factor: usize,
+    intensity: f64,
+) {
+    let row_size = width * 3;
+    let stride = stride.unwrap_or(row_size);
+    let period = factor.max(2);
+    let scale = 1.0 - intensity.clamp(0.0, 1.0);
+    for y in (period - 1..height).step_by(period) {
+        for byte in buf[y * stride..y * stride + row_size].iter_mut() {
+            *byte = (*byte as f64 * scale).round() as u8;
+        }
+    }
+}
+
+// https://www.scale2x.it/algorithm
+//   A B C
+//   D E F
+//   G H I
+fn scale2x(frame: &Frame, x: usize, y: usize, out: &mut [Rgb; 9]) {
+    let b = frame.get(x, y, 0, -1);
+    let d = frame.get(x, y, -1, 0);
+    let e = frame.get(x, y, 0, 0);
+    let f = frame.get(x, y, 1, 0);
+    let h = frame.get(x, y, 0, 1);
+    if b != h && d != f {
+        out[0] = if d == b { d } else { e };
+        out[1] = if b == f { f } else { e };
+        out[2] = if d == h { d } else { e };
+        out[3] = if h == f { f } else { e };
+    } else {
+        out[..4].copy_from_slice(&[e; 4]);
+    }
+}
+
+fn scale3x(frame: &Frame, x: usize, y: usize, out: &mut [Rgb; 9]) {
+    let a = frame.get(x, y, -1, -1);
+    let b = frame.get(x, y, 0, -1);
+    let c = frame.get(x, y, 1, -1);
+    let d = frame.get(x, y, -1, 0);
+    let e = frame.get(x, y, 0, 0);
+    let f = frame.get(x, y, 1, 0);
+    let g = frame.get(x, y, -1, 1);
+    let h = frame.get(x, y, 0, 1);
+    let i = frame.get(x, y, 1, 1);
+    if b != h && d != f {
+        out[0] = if d == b { d } else { e };
+        out[1] = if (d == b && e != c) || (b == f && e != a) {
+            b
+        } else {
+            e
+        };
+        out[2] = if b == f { f } else { e };
+        out[3] = if (d == b && e != g) || (d == h && e != a) {
+            d
+        } else {
+            e
+        };
+        out[4] = e;
+        out[5] = if (b == f && e != i) || (h == f && e != c) {
+            f
+        } else {
+            e
+        };
+        out[6] = if d == h { d } else { e };
+        out[7] = if (d == h && e != i) || (h == f && e != g) {
+            h
+        } else {
+            e
+        };
+        out[8] = if h == f { f } else { e };
+    } else {
+        *out = [e; 9];
+    }
+}
+
+// Colors count as different when they're past hqx's thresholds in YUV space.
+// https://en.wikipedia.org/wiki/Hqx
+fn yuv([r, g, b]: Rgb) -> (f64, f64, f64) {
+    let (r, g, b) = (r as f64, g as f64, b as f64);
+    (
+        0.299 * r + 0.587 * g + 0.114 * b,
+        -0.169 * r - 0.331 * g + 0.5 * b,
+        0.5 * r - 0.419 * g - 0.081 * b,
+    )
+}
+
+fn is_different(p: Rgb, q: Rgb) -> bool {
+    let (y1, u1, v1) = yuv(p);
+    let (y2, u2, v2) = yuv(q);
+    (y1 - y2).abs() > 48.0 || (u1 - u2).abs() > 7.0 || (v1 - v2).abs() > 6.0
+}
+
+// Weighted sum of colors, with weights summing to `total`
+fn blend(colors: &[(Rgb, u32)], total: u32) -> Rgb {
+    let mut ret = [0; 3];
+    for (channel, out) in ret.iter_mut().enumerate() {
+        let sum: u32 = colors
+            .iter()
+            .map(|(color, weight)| color[channel] as u32 * weight)
+            .sum();
+        *out = ((sum + total / 2) / total) as u8;
+    }
+    ret
+}
+
+// hq2x: https://en.wikipedia.org/wiki/Hqx
+// Indexed by which of the 8 neighbors are different from the center, with bits in the order
+// A B C D F G H I(A is bit 0). Each entry picks one of the blends in `hq2x_corner`.
+#[rustfmt::skip]
+const HQ2X_RULES: [u8; 256] = [
+    4, 4, 6, 2, 4, 4, 6, 2, 5, 3, 10, 7, 5, 3, 12, 8,
+    4, 4, 6, 13, 4, 4, 6, 13, 5, 3, 7, 7, 5, 3, 1, 7,
+    4, 4, 6, 2, 4, 4, 6, 2, 5, 3, 12, 8, 5, 3, 11, 9,
+    4, 4, 6, 13, 4, 4, 6, 13, 5, 3, 11, 7, 5, 3, 1, 9,
+    4, 4, 6, 2, 4, 4, 6, 2, 5, 14, 7, 7, 5, 14, 11, 7,
+    4, 4, 6, 2, 4, 4, 6, 2, 5, 3, 11, 7, 5, 3, 11, 7,
+    4, 4, 6, 2, 4, 4, 6, 2, 5, 14, 1, 7, 5, 14, 1, 9,
+    4, 4, 6, 2, 4, 4, 6, 13, 5, 3, 11, 7, 5, 14, 1, 9,
+    4, 4, 6, 2, 4, 4, 6, 2, 5, 3, 10, 7, 5, 3, 12, 8,
+    4, 4, 6, 2, 4, 4, 6, 2, 5, 3, 11, 7, 5, 3, 11, 7,
+    4, 4, 6, 2, 4, 4, 6, 2, 5, 3, 12, 8, 5, 3, 11, 9,
+    4, 4, 6, 2, 4, 4, 6, 2, 5, 3, 11, 7, 5, 3, 1, 9,
+    4, 4, 6, 2, 4, 4, 6, 2, 5, 3, 11, 7, 5, 3, 11, 7,
+    4, 4, 6, 2, 4, 4, 6, 2, 5, 3, 11, 7, 5, 3, 11, 7,
+    4, 4, 6, 2, 4, 4, 6, 2, 5, 3, 11, 7, 5, 3, 1, 9,
+    4, 4, 6, 2, 4, 4, 6, 2, 5, 3, 11, 7, 5, 3, 1, 9,
+];
+
+// The output corner pointed at by dx and dy. Written for the top-left corner:
+//   A B C
+//   D E F
+//   G H I
+// Other corners mirror the neighborhood.
+fn hq2x_corner(frame: &Frame, x: usize, y: usize, dx: isize, dy: isize) -> Rgb {
+    let p = |px: isize, py: isize| frame.get(x, y, -px * dx, -py * dy);
+    let e = p(0, 0);
+    let neighbors = [
+        p(-1, -1),
+        p(0, -1),
+        p(1, -1),
+        p(-1, 0),
+        p(1, 0),
+        p(-1, 1),
+        p(0, 1),
+        p(1, 1),
+    ];
+    let pattern = neighbors
+        .iter()
+        .enumerate()
+        .filter(|(_, w)| is_different(e, **w))
+        .fold(0, |acc, (bit, _)| acc | 1 << bit);
+    let (a, b, d, f, h) = (
+        neighbors[0],
+        neighbors[1],
+        neighbors[3],
+        neighbors[4],
+        neighbors[6],
+    );
+    let edge = !is_different(b, d);
+    match HQ2X_RULES[pattern] {
+        1 => blend(&[(e, 3), (a, 1)], 4),
+        2 => blend(&[(e, 3), (d, 1)], 4),
+        3 => blend(&[(e, 3), (b, 1)], 4),
+        4 => blend(&[(e, 2), (d, 1), (b, 1)], 4),
+        5 => blend(&[(e, 2), (a, 1), (b, 1)], 4),
+        6 => blend(&[(e, 2), (a, 1), (d, 1)], 4),
+        7 if edge => blend(&[(e, 2), (d, 1), (b, 1)], 4),
+        8 if edge => blend(&[(e, 14), (d, 1), (b, 1)], 16),
+        9 if edge => blend(&[(e, 2), (d, 3), (b, 3)], 8),
+        7 | 8 | 9 => e,
+        10 if edge => blend(&[(e, 2), (d, 1), (b, 1)], 4),
+        11 if edge => blend(&[(e, 6), (d, 1), (b, 1)], 8),
+        12 if edge => blend(&[(e, 2), (d, 3), (b, 3)], 8),
+        10 | 11 | 12 => blend(&[(e, 3), (a, 1)], 4),
+        13 if !is_different(b, f) => blend(&[(e, 5), (b, 2), (d, 1)], 8),
+        13 => blend(&[(e, 3), (d, 1)], 4),
+        14 if !is_different(d, h) => blend(&[(e, 5), (d, 2), (b, 1)], 8),
+        14 => blend(&[(e, 3), (b, 1)], 4),
+        rule => unreachable!("Unknown hq2x rule {}", rule),
+    }
+}
+
+fn hq2x(frame: &Frame, x: usize, y: usize, out: &mut [Rgb; 9]) {
+    out[0] = hq2x_corner(frame, x, y, -1, -1);
+    out[1] = hq2x_corner(frame, x, y, 1, -1);
+    out[2] = hq2x_corner(frame, x, y, -1, 1);
+    out[3] = hq2x_corner(frame, x, y, 1, 1);
+}
+
+// xBR level 1: https://forums.libretro.com/t/xbr-algorithm-tutorial/123
+fn xbr_distance(p: Rgb, q: Rgb) -> f64 {
+    let (y1, u1, v1) = yuv(p);
+    let (y2, u2, v2) = yuv(q);
+    48.0 * (y1 - y2).abs() + 7.0 * (u1 - u2).abs() + 6.0 * (v1 - v2).abs()
+}
+
+// The output corner pointed at by dx and dy. Written for the bottom-right corner:
+//       A1 B1 C1
+//    A0 A  B  C  C4
+//    D0 D  E  F  F4
+//    G0 G  H  I  I4
+//       G5 H5 I5
+// Other corners mirror the neighborhood.
+fn xbr_corner(frame: &Frame, x: usize, y: usize, dx: isize, dy: isize) -> Rgb {
+    let p = |px: isize, py: isize| frame.get(x, y, px * dx, py * dy);
+    let (b, c, d, e, f) = (p(0, -1), p(1, -1), p(-1, 0), p(0, 0), p(1, 0));
+    let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+    let (f4, i4, h5, i5) = (p(2, 0), p(2, 1), p(0, 2), p(1, 2));
+    if e == f || e == h {
+        return e;
+    }
+    let dist = xbr_distance;
+    let edge_weight = dist(e, c) + dist(e, g) + dist(i, f4) + dist(i, h5) + 4.0 * dist(h, f);
+    let other_weight = dist(h, d) + dist(h, i5) + dist(f, i4) + dist(f, b) + 4.0 * dist(e, i);
+    if edge_weight < other_weight {
+        let nearest = if dist(e, f) <= dist(e, h) { f } else { h };
+        blend(&[(e, 1), (nearest, 1)], 2)
+    } else {
+        e
+    }
+}
+
+fn xbr2x(frame: &Frame, x: usize, y: usize, out: &mut [Rgb; 9]) {
+    out[0] = xbr_corner(frame, x, y, -1, -1);
+    out[1] = xbr_corner(frame, x, y, 1, -1);
+    out[2] = xbr_corner(frame, x, y, -1, 1);
+    out[3] = xbr_corner(frame, x, y, 1, 1);
+}
+
+mod tests {
+    use super::*;
+
+    const W: Rgb = [255, 255, 255];
+    const K: Rgb = [0, 0, 0];
+
+    fn frame(pixels: &[Rgb]) -> Vec<u8> {
+        pixels.iter().flat_map(|p| p.iter().cloned()).collect()
+    }
+    fn scale(scaler: Scaler, pixels: &[Rgb], width: usize) -> Vec<Rgb> {
+        let height = pixels.len() / width;
+        let factor = scaler.factor();
+        let mut out = vec![0; width * height * factor * factor * 3];
+        scale_frame(scaler, &frame(pixels), width, height, &mut out, None);
+        out.chunks(3).map(|p| [p[0], p[1], p[2]]).collect()
+    }
+
+    // A diagonal line from the bottom-left to the top-right
+    const DIAGONAL: [Rgb; 9] = [K, K, W, K, W, K, W, K, K];
+
+    #[test]
+    fn test_flat_frames_are_unchanged() {
+        for scaler in [
+            Scaler::None,
+            Scaler::Scale2x,
+            Scaler::Scale3x,
+            Scaler::Hq2x,
+            Scaler::Xbr2x,
+        ]
+        .iter()
+        {
+            let color = [10, 200, 30];
+            let out = scale(*scaler, &[color; 16], 4);
+            assert_eq!(out.len(), 16 * scaler.factor() * scaler.factor());
+            assert!(out.iter().all(|p| *p == color), "{:?}", scaler);
+        }
+    }
+    #[test]
+    fn test_scale2x() {
+        let out = scale(Scaler::Scale2x, &DIAGONAL, 3);
+        // The center pixel's 2x2 block at (2, 2) fills in the staircase's inside corners
+        let block = |x: usize, y: usize| out[y * 6 + x];
+        assert_eq!(block(2, 2), W);
+        assert_eq!(block(3, 2), W);
+        assert_eq!(block(2, 3), W);
+        assert_eq!(block(3, 3), W);
+        // A black pixel next to the line is cut into by the line
+        assert_eq!(block(3, 1), W);
+        assert_eq!(block(2, 1), K);
+        assert_eq!(Scaler::from_str("Scale2x"), Ok(Scaler::Scale2x));
+        assert!(Scaler::from_str("scale9x").is_err());
+    }
+    #[test]
+    fn test_scale3x() {
+        let out = scale(Scaler::Scale3x, &DIAGONAL, 3);
+        let block = |x: usize, y: usize| out[y * 9 + x];
+        // The center of each source pixel keeps its color
+        for y in 0..3 {
+            for x in 0..3 {
+                assert_eq!(block(x * 3 + 1, y * 3 + 1), DIAGONAL[y * 3 + x]);
+            }
+        }
+        // The black pixel above the center gets the line's corner
+        assert_eq!(block(5, 2), W);
+        assert_eq!(block(3, 0), K);
+    }
+    #[test]
+    fn test_hq2x_blends_diagonals() {
+        let out = scale(Scaler::Hq2x, &DIAGONAL, 3);
+        let block = |x: usize, y: usize| out[y * 6 + x];
+        // The corner of the black pixel the line passes is partly white
+        let corner = block(3, 1);
+        assert!(corner[0] > 0 && corner[0] < 255, "{:?}", corner);
+        // The corner of the black pixel in the background stays black
+        assert_eq!(block(0, 0), K);
+    }
+    #[test]
+    fn test_hq2x_rules_are_symmetric() {
+        // Mirroring the neighborhood across the corner's diagonal swaps B with D, C with G and F
+        // with H, and the rules blending towards one of them.
+        let mirror_bit = [0, 3, 5, 1, 6, 2, 4, 7];
+        let mirror_rule = |rule: u8| match rule {
+            2 => 3,
+            3 => 2,
+            5 => 6,
+            6 => 5,
+            13 => 14,
+            14 => 13,
+            rule => rule,
+        };
+        for pattern in 0..256 {
+            let mirrored = (0..8)
+                .filter(|bit| pattern & 1 << bit != 0)
+                .fold(0, |acc, bit| acc | 1 << mirror_bit[bit]);
+            assert_eq!(
+                HQ2X_RULES[mirrored],
+                mirror_rule(HQ2X_RULES[pattern]),
+                "{}",
+                pattern
+            );
+        }
+    }
+    #[test]
+    fn test_xbr_smooths_edges() {
+        // A 45 degree edge between a white and black half
+        #[rustfmt::skip]
+        let pixels = [
+            W, W, W, W, K,
+            W, W, W, K, K,
+            W, W, K, K, K,
+            W, K, K, K, K,
+            K, K, K, K, K,
+        ];
+        let out = scale(Scaler::Xbr2x, &pixels, 5);
+        let block = |x: usize, y: usize| out[y * 10 + x];
+        // The black pixel at (2, 2) has its top-left corner blended with white
+        let corner = block(4, 4);
+        assert!(corner[0] > 0 && corner[0] < 255, "{:?}", corner);
+        assert_eq!(block(5, 5), K);
+    }
+    #[test]
+    fn test_apply_scanlines() {
+        let mut buf = vec![200; 2 * 4 * 3];
+        apply_scanlines(&mut buf, 2, 4, None, 2, 0.5);
+        let rows: Vec<u8> = buf.chunks(6).map(|row| row[0]).collect();
+        assert_eq!(rows, vec![200, 100, 200, 100]);
+    }
+}
@@ -0,0 +1,160 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use crate::joystick::Joystick;
+use crate::mapper::AddressSpace;
+use crate::nes::{load_ines, read_ines, Nes};
+
+use std::io;
+
+// Blargg's test ROMs report their results through PRG-RAM:
+// https://github.com/christopherpow/nes-test-roms/blob/master/instr_test-v5/readme.txt
+const ADDRESS_STATUS: u16 = 0x6000;
+const ADDRESS_SIGNATURE: u16 = 0x6001;
+const ADDRESS_MESSAGE: u16 = 0x6004;
+const ADDRESS_PRG_RAM_END: u16 = 0x7FFF;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_NEEDS_RESET: u8 = 0x81;
+// The ROM asks for the reset button to be pressed no sooner than 100ms later.
+const RESET_DELAY_FRAMES: u32 = 6;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TestRomStatus {
+    Passed,
+    Failed(u8),
+    TimedOut,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestRomResult {
+    pub status: TestRomStatus,
+    pub message: String,
+    pub frames: u32,
+}
+
+impl TestRomResult {
+    pub fn is_passed(&self) -> bool {
+        self.status == TestRomStatus::Passed
+    }
+}
+
+pub fn run_test_rom(filename: String, max_frames: u32) -> Result<TestRomResult, io::Error> {
+    let rom = read_ines(filename)?;
+    let mut nes = load_ines(rom, Box::new(Joystick::new()), Box::new(Joystick::new()));
+    nes.apu.is_recording = false;
+    Ok(run_test_nes(&mut nes, max_frames))
+}
+
+// Runs frames until the status byte leaves "running", pressing reset whenever the ROM asks for it.
+pub fn run_test_nes(nes: &mut Nes, max_frames: u32) -> TestRomResult {
+    let mut reset_frame = None;
+    for frame in 0..max_frames {
+        nes.run_frame();
+        if !has_signature(nes) {
+            continue;
+        }
+        match nes.cpu.peek(ADDRESS_STATUS) {
+            STATUS_RUNNING => {}
+            STATUS_NEEDS_RESET => match reset_frame {
+                None => reset_frame = Some(frame + RESET_DELAY_FRAMES),
+                Some(reset_at) if frame >= reset_at => {
+                    nes.reset();
+                    reset_frame = None;
+                }
+                Some(_) => {}
+            },
+            code => {
+                return TestRomResult {
+                    status: if code == 0 {
+                        TestRomStatus::Passed
+                    } else {
+                        TestRomStatus::Failed(code)
+                    },
+                    message: read_message(nes),
+                    frames: frame + 1,
+                };
+            }
+        }
+    }
+    TestRomResult {
+        status: TestRomStatus::TimedOut,
+        message: if has_signature(nes) {
+            read_message(nes)
+        } else {
+            String::new()
+        },
+        frames: max_frames,
+    }
+}
+
+fn has_signature(nes: &Nes) -> bool {
+    (0..SIGNATURE.len()).all(|i| nes.cpu.peek(ADDRESS_SIGNATURE + i as u16) == SIGNATURE[i])
+}
+
+fn read_message(nes: &Nes) -> String {
+    let mut bytes = Vec::new();
+    for ptr in ADDRESS_MESSAGE..=ADDRESS_PRG_RAM_END {
+        let byte = nes.cpu.peek(ptr);
+        if byte == 0 {
+            break;
+        }
+        bytes.push(byte);
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::Write;
+
+    // Assembles a mapper 0 ROM that runs `program` from $8000 and then loops forever.
+    fn write_test_rom(name: &str, program: &[u8]) -> String {
+        let mut prg = vec![0u8; 0x4000];
+        prg[..program.len()].copy_from_slice(program);
+        let loop_ptr = 0x8000 + program.len() as u16;
+        prg[program.len()..program.len() + 3].copy_from_slice(&[
+            0x4C,
+            (loop_ptr & 0xFF) as u8,
+            (loop_ptr >> 8) as u8,
+        ]); // JMP loop
+        prg[0x3FFC] = 0x00; // Reset vector = $8000
+        prg[0x3FFD] = 0x80;
+        let path = std::env::temp_dir().join(name);
+        let mut fh = File::create(&path).unwrap();
+        fh.write_all(&[0x4E, 0x45, 0x53, 0x1A, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        fh.write_all(&prg).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn store(program: &mut Vec<u8>, ptr: u16, v: u8) {
+        program.extend_from_slice(&[0xA9, v, 0x8D, (ptr & 0xFF) as u8, (ptr >> 8) as u8]);
+    }
+
+    #[test]
+    fn test_reports_result() {
+        let mut program = vec![];
+        store(&mut program, 0x6000, STATUS_RUNNING);
+        for (i, byte) in SIGNATURE.iter().enumerate() {
+            store(&mut program, ADDRESS_SIGNATURE + i as u16, *byte);
+        }
+        for (i, byte) in b"Failed #3\0".iter().enumerate() {
+            store(&mut program, ADDRESS_MESSAGE + i as u16, *byte);
+        }
+        store(&mut program, 0x6000, 3);
+        let filename = write_test_rom("nes_emulator_test_rom_result.nes", &program);
+        let result = run_test_rom(filename, 10).unwrap();
+        assert_eq!(result.status, TestRomStatus::Failed(3));
+        assert_eq!(result.message, "Failed #3");
+    }
+    #[test]
+    fn test_times_out() {
+        let filename = write_test_rom("nes_emulator_test_rom_timeout.nes", &[]);
+        let result = run_test_rom(filename, 2).unwrap();
+        assert_eq!(result.status, TestRomStatus::TimedOut);
+        assert_eq!(result.frames, 2);
+    }
+}
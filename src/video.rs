@@ -0,0 +1,66 @@
+#![allow(unused_imports)]
+
+use crate::ntsc_filter::{NtscFilter, NTSC_HEIGHT, NTSC_WIDTH};
+use crate::ppu::{PixelFormat, Ppu, RENDER_HEIGHT, RENDER_WIDTH};
+use crate::scaler::{apply_scanlines, scale_frame, Scaler};
+
+// Turns the PPU's frame into the RGB24 image a frontend displays: an optional NTSC filter,
+// then a scaler, then scanlines.
+pub struct VideoPipeline {
+    pub ntsc_filter: Option<NtscFilter>,
+    pub scaler: Scaler,
+    pub scanlines: f64, // 0.0 = off, 1.0 = black
+    unscaled: Vec<u8>,
+}
+
+impl VideoPipeline {
+    pub fn new(ntsc_filter: Option<NtscFilter>, scaler: Scaler, scanlines: f64) -> VideoPipeline {
+        VideoPipeline {
+            ntsc_filter,
+            scaler,
+            scanlines,
+            unscaled: Vec::new(),
+        }
+    }
+    // Before scaling
+    pub fn source_size(&self) -> (usize, usize) {
+        match self.ntsc_filter {
+            None => (RENDER_WIDTH, RENDER_HEIGHT),
+            Some(_) => (NTSC_WIDTH, NTSC_HEIGHT),
+        }
+    }
+    pub fn output_size(&self) -> (usize, usize) {
+        let (width, height) = self.source_size();
+        let factor = self.scaler.factor();
+        (width * factor, height * factor)
+    }
+    pub fn render_into(&mut self, ppu: &Ppu, buf: &mut [u8], stride: Option<usize>) {
+        let (width, height) = self.source_size();
+        let (out_width, out_height) = self.output_size();
+        if self.scaler == Scaler::None {
+            self.render_source(ppu, buf, stride);
+        } else {
+            let mut unscaled = std::mem::take(&mut self.unscaled);
+            unscaled.resize(width * height * 3, 0);
+            self.render_source(ppu, &mut unscaled, None);
+            scale_frame(self.scaler, &unscaled, width, height, buf, stride);
+            self.unscaled = unscaled;
+        }
+        if self.scanlines > 0.0 {
+            let factor = self.scaler.factor();
+            apply_scanlines(buf, out_width, out_height, stride, factor, self.scanlines);
+        }
+    }
+    pub fn render(&mut self, ppu: &Ppu) -> Vec<u8> {
+        let (width, height) = self.output_size();
+        let mut buf = vec![0; width * height * 3];
+        self.render_into(ppu, &mut buf, None);
+        buf
+    }
+    fn render_source(&mut self, ppu: &Ppu, buf: &mut [u8], stride: Option<usize>) {
+        match &mut self.ntsc_filter {
+            None => ppu.render_into(buf, PixelFormat::Rgb24, stride),
+            Some(filter) => filter.render_into(ppu, buf, PixelFormat::Rgb24, stride),
+        }
+    }
+}
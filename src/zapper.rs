@@ -0,0 +1,211 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use core::cell::Cell;
+use std::io::Read;
+use std::io::Write;
+
+use crate::common::get_bit;
+use crate::joystick::{DATA_LINE_D0, DATA_LINE_D3, DATA_LINE_D4};
+use crate::mapper::AddressSpace;
+use crate::ppu::{Ppu, RENDER_HEIGHT, RENDER_WIDTH};
+use crate::serialization::Savable;
+
+// https://wiki.nesdev.com/w/index.php/Zapper
+// The sensor sees a small area around the pointer, and stays lit for a while after the beam
+// passes over a bright pixel.
+const SENSOR_RADIUS: i32 = 2;
+const LIGHT_PERSISTENCE_SCANLINES: i32 = 20;
+// Average of the RGB channels
+const LIGHT_THRESHOLD: u32 = 85;
+
+// Vs. System light guns report over $4016 like a controller, one bit per read.
+const VS_ALWAYS_SET: u8 = 0x10;
+const VS_LIGHT: u8 = 0x40;
+const VS_TRIGGER: u8 = 0x80;
+
+pub struct Zapper {
+    ppu: *const Ppu,
+    pointer: Option<(u16, u16)>,
+    is_trigger_pulled: bool,
+    is_vs: bool,
+    // Vs. System only
+    shift_register: Cell<u8>,
+    strobe_active: bool,
+}
+
+impl Savable for Zapper {
+    // The pointer and trigger are inputs, like a joystick's buttons.
+    fn save(&self, fh: &mut dyn Write) {
+        self.shift_register.get().save(fh);
+        self.strobe_active.save(fh);
+    }
+    fn load(&mut self, fh: &mut dyn Read) {
+        let mut shift_register = 0;
+        shift_register.load(fh);
+        self.shift_register.set(shift_register);
+        self.strobe_active.load(fh);
+    }
+}
+
+impl Zapper {
+    // For $4017 on the NES and Famicom. The PPU must outlive the Zapper.
+    pub fn new(ppu: &Ppu) -> Zapper {
+        Zapper {
+            ppu,
+            pointer: None,
+            is_trigger_pulled: false,
+            is_vs: false,
+            shift_register: Cell::new(0),
+            strobe_active: false,
+        }
+    }
+    // For $4016 on the Vs. System
+    pub fn new_vs(ppu: &Ppu) -> Zapper {
+        Zapper {
+            is_vs: true,
+            ..Zapper::new(ppu)
+        }
+    }
+    // In screen pixels, or None when pointing away from the screen
+    pub fn set_pointer(&mut self, pointer: Option<(u16, u16)>) {
+        self.pointer =
+            pointer.filter(|&(x, y)| (x as usize) < RENDER_WIDTH && (y as usize) < RENDER_HEIGHT);
+    }
+    pub fn set_trigger(&mut self, is_trigger_pulled: bool) {
+        self.is_trigger_pulled = is_trigger_pulled;
+    }
+    // Whether a bright pixel near the pointer was drawn within the last few scanlines
+    pub fn is_light_detected(&self) -> bool {
+        let (pointer_x, pointer_y) = match self.pointer {
+            None => return false,
+            Some((x, y)) => (x as i32, y as i32),
+        };
+        let ppu = unsafe { &*self.ppu };
+        let scanline = ppu.current_scanline() as i32;
+        let cycle = ppu.current_cycle() as i32;
+        for y in pointer_y - SENSOR_RADIUS..=pointer_y + SENSOR_RADIUS {
+            let elapsed = scanline - y;
+            if y < 0 || y >= RENDER_HEIGHT as i32 {
+                continue;
+            }
+            if !(0..=LIGHT_PERSISTENCE_SCANLINES).contains(&elapsed) {
+                continue;
+            }
+            for x in pointer_x - SENSOR_RADIUS..=pointer_x + SENSOR_RADIUS {
+                // Pixel x is drawn on dot x + 1
+                if x < 0 || x >= RENDER_WIDTH as i32 || (elapsed == 0 && x + 1 >= cycle) {
+                    continue;
+                }
+                if pixel_brightness(ppu, x as usize, y as usize) >= LIGHT_THRESHOLD {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+    fn vs_report(&self) -> u8 {
+        let mut report = VS_ALWAYS_SET;
+        if self.is_light_detected() {
+            report |= VS_LIGHT;
+        }
+        if self.is_trigger_pulled {
+            report |= VS_TRIGGER;
+        }
+        report
+    }
+}
+
+fn pixel_brightness(ppu: &Ppu, x: usize, y: usize) -> u32 {
+    let i = y * RENDER_WIDTH + x;
+    let color = (ppu.display_emphasis[i] as usize) << 6 | (ppu.display[i] & 0x3F) as usize;
+    let (r, g, b) = ppu.palette()[color];
+    (r as u32 + g as u32 + b as u32) / 3
+}
+
+impl AddressSpace for Zapper {
+    // D3 is low while light is detected, and D4 is high while the trigger is pulled.
+    fn peek(&self, _ptr: u16) -> u8 {
+        if self.is_vs {
+            if self.strobe_active {
+                self.shift_register.set(self.vs_report());
+            }
+            let register = self.shift_register.get();
+            self.shift_register.set((register >> 1) | 0x80);
+            return register & DATA_LINE_D0;
+        }
+        let mut data = DATA_LINE_D3;
+        if self.is_light_detected() {
+            data &= !DATA_LINE_D3;
+        }
+        if self.is_trigger_pulled {
+            data |= DATA_LINE_D4;
+        }
+        data
+    }
+    fn poke(&mut self, _ptr: u16, v: u8) {
+        self.strobe_active = get_bit(v, 0) > 0;
+        if self.strobe_active {
+            self.shift_register.set(self.vs_report());
+        }
+    }
+}
+
+mod tests {
+    use super::*;
+    use crate::common::Clocked;
+    use crate::mapper::Ram;
+
+    // A frame with a white square at (100..110, 50..60) on black, drawn up to `scanline`
+    fn test_ppu(scanline: u16) -> Box<Ppu> {
+        let mut ppu = Box::new(Ppu::new());
+        ppu.mapper = Box::new(Ram::new(0x4000));
+        ppu.display = [0x0F; RENDER_WIDTH * RENDER_HEIGHT];
+        for y in 50..60 {
+            for x in 100..110 {
+                ppu.display[y * RENDER_WIDTH + x] = 0x30;
+            }
+        }
+        while ppu.current_scanline() != scanline {
+            ppu.clock();
+        }
+        ppu
+    }
+
+    #[test]
+    fn test_light_sensor() {
+        let ppu = test_ppu(62);
+        let mut zapper = Zapper::new(&ppu);
+        assert_eq!(zapper.peek(0x4017), DATA_LINE_D3);
+        zapper.set_pointer(Some((105, 55)));
+        assert_eq!(zapper.peek(0x4017), 0);
+        zapper.set_trigger(true);
+        assert_eq!(zapper.peek(0x4017), DATA_LINE_D4);
+        zapper.set_pointer(Some((20, 55)));
+        assert_eq!(zapper.peek(0x4017), DATA_LINE_D3 | DATA_LINE_D4);
+    }
+    #[test]
+    fn test_light_timing() {
+        // The beam hasn't reached the square yet
+        let ppu = test_ppu(40);
+        let mut zapper = Zapper::new(&ppu);
+        zapper.set_pointer(Some((105, 55)));
+        assert_eq!(zapper.is_light_detected(), false);
+        // The sensor has gone dark again
+        let ppu = test_ppu(100);
+        let mut zapper = Zapper::new(&ppu);
+        zapper.set_pointer(Some((105, 55)));
+        assert_eq!(zapper.is_light_detected(), false);
+    }
+    #[test]
+    fn test_vs_zapper() {
+        let ppu = test_ppu(62);
+        let mut zapper = Zapper::new_vs(&ppu);
+        zapper.set_pointer(Some((105, 55)));
+        zapper.set_trigger(true);
+        zapper.poke(0x4016, 1);
+        zapper.poke(0x4016, 0);
+        let report = (0..8).fold(0, |report, i| report | zapper.peek(0x4016) << i);
+        assert_eq!(report, VS_ALWAYS_SET | VS_LIGHT | VS_TRIGGER);
+    }
+}
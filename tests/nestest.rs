@@ -0,0 +1,76 @@
+// Runs nestest in automation mode and compares every instruction against the reference log.
+// http://www.qmtpro.com/~nes/misc/nestest.txt
+//
+// The ROM and log aren't distributed with the emulator. Place them at roms/nestest.nes and
+// roms/nestest.log to enable this test.
+
+use nes_emulator::c6502::C6502Registers;
+use nes_emulator::joystick::Joystick;
+use nes_emulator::nes::{load_ines, read_ines};
+
+use std::fs;
+use std::path::Path;
+
+const ROM_PATH: &str = "roms/nestest.nes";
+const LOG_PATH: &str = "roms/nestest.log";
+
+// nestest.log lines look like:
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+fn parse_log_line(line: &str) -> C6502Registers {
+    let register = |name: &str| -> u8 {
+        let start = line
+            .find(name)
+            .unwrap_or_else(|| panic!("Missing {} in log line {:?}", name, line))
+            + name.len();
+        u8::from_str_radix(&line[start..start + 2], 16).unwrap()
+    };
+    C6502Registers {
+        pc: u16::from_str_radix(&line[0..4], 16).unwrap(),
+        acc: register(" A:"),
+        x: register(" X:"),
+        y: register(" Y:"),
+        status: register(" P:"),
+        sp: register(" SP:"),
+    }
+}
+
+fn format_registers(r: &C6502Registers) -> String {
+    format!(
+        "{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+        r.pc, r.acc, r.x, r.y, r.status, r.sp
+    )
+}
+
+#[test]
+fn test_nestest_golden_log() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let rom_path = root.join(ROM_PATH);
+    let log_path = root.join(LOG_PATH);
+    if !rom_path.exists() || !log_path.exists() {
+        eprintln!(
+            "Skipping nestest: {} or {} not found",
+            rom_path.display(),
+            log_path.display()
+        );
+        return;
+    }
+
+    let rom = read_ines(rom_path.to_str().unwrap().to_string()).unwrap();
+    let mut nes = load_ines(rom, Box::new(Joystick::new()), Box::new(Joystick::new()));
+    nes.cpu.initialize_automation();
+
+    let log = fs::read_to_string(log_path).unwrap();
+    for (i, line) in log.lines().enumerate() {
+        let expected = parse_log_line(line);
+        let actual = nes.cpu.registers();
+        assert!(
+            expected == actual,
+            "nestest diverged at line {}\nexpected: {}\nactual:   {}\nlog:      {}",
+            i + 1,
+            format_registers(&expected),
+            format_registers(&actual),
+            line
+        );
+        nes.cpu.run_instructions(1);
+    }
+}